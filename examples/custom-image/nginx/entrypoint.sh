@@ -2,12 +2,16 @@
 
 # First we need to finalize the configuration of our server.
 # We need to put in the resolver address and DNS name validity period.
+# root.conf is part of the image rather than a config map, so it is filled in
+# here. The placeholders differ from the {{ ... }} ones cm-bump renders in the
+# config maps annotated with cm-bump/template: "true", which can refer to the
+# same values as {{ env.RESOLVER }} and {{ env.DNS_TTL }}.
 
-RESOLVER=`cat /etc/resolv.conf | grep nameserver | head -1 | cut -d' ' -f1 --complement`
-DNS_TTL=${DNS_TTL:-1m}
+export RESOLVER=`cat /etc/resolv.conf | grep nameserver | head -1 | cut -d' ' -f1 --complement`
+export DNS_TTL=${DNS_TTL:-1m}
 
-sed -i -E "s/\\{\\{RESOLVER\\}\\}/$RESOLVER/g" /tmp/nginx/root.conf
-sed -i -E "s/\\{\\{DNS_TTL\\}\\}/$DNS_TTL/g" /tmp/nginx/root.conf
+sed -i -E "s/@RESOLVER@/$RESOLVER/g" /tmp/nginx/root.conf
+sed -i -E "s/@DNS_TTL@/$DNS_TTL/g" /tmp/nginx/root.conf
 
 # prepare the location where cm-bump will store the configurations
 mkdir /tmp/nginx/locations
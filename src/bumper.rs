@@ -1,14 +1,20 @@
-use log;
+use super::container::ContainerRef;
+use super::pidfd::{self, PidFd};
+use super::procfs::{ProcFs, Stat};
+use super::ratelimit::RateLimiter;
+use super::sequence::{self, Step, Target};
 use nix::sys::signal::{self, Signal};
-use nix::unistd::Pid;
+use nix::unistd::{getpid, Pid};
 use regex::Regex;
+use std::collections::HashSet;
 use std::fs;
-use std::path::Path;
-use std::str;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 #[derive(Debug, Clone, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("Initialization error: {0}")]
     InitError(String),
@@ -22,15 +28,59 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// How often to look for the process when waiting for it to appear.
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The kernel truncates the name of a process in `/proc/<pid>/comm` to this many bytes.
+const COMM_MAX_LEN: usize = 15;
+
 #[derive(Debug, Clone)]
 pub enum ProcessDetection {
     Cmdline(Regex),
     Pid(i32),
+    /// The PID is read from the file each time the process is looked up. If the regex is specified, the cmdline of
+    /// the process needs to match it, too, to guard against stale pidfiles pointing to reused PIDs.
+    PidFile(PathBuf, Option<Regex>),
+    /// The path of the executable of the process, as read from `/proc/<pid>/exe`.
+    Exe(PathBuf),
+    /// The name of the process, as read from `/proc/<pid>/comm`.
+    Comm(String),
+    /// The effective UID of the owner of the process.
+    Uid(u32),
+    /// The container the process runs in, as determined from `/proc/<pid>/cgroup`.
+    Container(ContainerRef),
+    /// The process needs to match all the detections.
+    All(Vec<ProcessDetection>),
+}
+
+/// Additional criteria for excluding processes when scanning the process list. The own process of cm-bump is always
+/// excluded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanOptions {
+    /// Exclude the parent, grand-parent, etc. of cm-bump, e.g. the shell that launched it.
+    pub exclude_ancestors: bool,
+    /// Exclude processes that have already exited but were not reaped by their parent.
+    pub exclude_zombies: bool,
+}
+
+/// How a process needs to be related to the process detected before it in the hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Relation {
+    /// The previous process needs to be the direct parent of the process.
+    #[default]
+    Parent,
+    /// The previous process needs to be an ancestor of the process, optionally at most the provided number of levels
+    /// above it.
+    Ancestor(Option<u32>),
 }
 
 #[derive(Debug, Clone)]
 struct ProcessDetector {
     detection: ProcessDetection,
+    scan_options: ScanOptions,
+    /// The relation to the parent detector, if any.
+    relation: Relation,
+    proc: ProcFs,
     pid: Option<Pid>,
     parent: Option<Box<ProcessDetector>>,
 }
@@ -38,7 +88,78 @@ struct ProcessDetector {
 #[derive(Debug, Clone)]
 pub struct Bumper {
     process_tree: ProcessDetector,
-    signal: Signal,
+    steps: Vec<Step>,
+    /// The pidfd of the last discovered process, kept across bumps.
+    target: Option<Arc<PidFd>>,
+    pidfd_supported: bool,
+    /// How long to wait for the process to appear if it hasn't been seen yet.
+    wait_timeout: Option<Duration>,
+    seen: bool,
+    rate_limiter: Option<RateLimiter>,
+    /// Whether a bump has been suppressed by the rate limiter and still needs to be done.
+    deferred: bool,
+    /// Until when a bump waits for the process to appear, if the process hadn't been seen yet when bumped.
+    waiting: Option<Instant>,
+    /// The signal sequence in progress.
+    run: Option<Run>,
+}
+
+/// A signal sequence in progress. The sequence is advanced from the tick so that its delays and waits for the
+/// processes to exit don't block.
+#[derive(Debug, Clone)]
+struct Run {
+    initial: Pid,
+    initial_pidfd: Option<Arc<PidFd>>,
+    /// The index of the next step.
+    step: usize,
+    /// When the next step is due, once its delay started.
+    due: Option<Instant>,
+    /// The processes signalled by the previous step that are waited for to exit, and until when.
+    exiting: Option<(Vec<Pid>, Instant)>,
+    /// Whether a signal has been sent yet.
+    signalled: bool,
+    /// Whether the sequence has been restarted because the process exited before the first signal.
+    retried: bool,
+}
+
+impl Run {
+    fn new(initial: Pid, initial_pidfd: Option<Arc<PidFd>>) -> Self {
+        Run {
+            initial,
+            initial_pidfd,
+            step: 0,
+            due: None,
+            exiting: None,
+            signalled: false,
+            retried: false,
+        }
+    }
+}
+
+/// Parses the name of a process to detect it by its comm. Longer names are rejected as they would never match the
+/// truncated comm of the process.
+pub fn parse_comm(comm: &str) -> Result<String> {
+    if comm.is_empty() {
+        Err(Error::InitError("The process name can't be empty.".into()))
+    } else if comm.len() > COMM_MAX_LEN {
+        Err(Error::InitError(format!(
+            "The process name `{}` is longer than {} bytes, the comm of the process is truncated to `{}`.",
+            comm,
+            COMM_MAX_LEN,
+            truncate(comm, COMM_MAX_LEN)
+        )))
+    } else {
+        Ok(comm.to_string())
+    }
+}
+
+/// Truncates the string to at most the provided number of bytes, on a character boundary.
+fn truncate(s: &str, max_len: usize) -> &str {
+    let mut end = max_len.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
 }
 
 impl ProcessDetector {
@@ -67,43 +188,21 @@ impl ProcessDetector {
 
         log::trace!("Checking whether the current PID {:?} is valid", self.pid);
 
-        if !self.valid() {
+        let related = self
+            .pid
+            .map(|pid| self.related_to(ppid, &pid))
+            .unwrap_or(false);
+
+        if !related || !self.valid() {
             log::trace!(
                 "Current PID {:?} determined not valid. Trying to rediscover.",
                 self.pid
             );
-            self.pid = match self.find_pid() {
-                Some(new_pid) if ppid.is_some() => match is_parent(&ppid.unwrap(), &new_pid) {
-                    Ok(yes) => {
-                        if yes {
-                            log::trace!("New PID found to be {}", new_pid);
-                            Some(new_pid)
-                        } else {
-                            log::trace!(
-                                "Forgetting the candidate PID {} because PPID doesn't match.",
-                                new_pid
-                            );
-                            None
-                        }
-                    }
-                    Err(e) => {
-                        log::error!(
-                            "Failed to determine parent process of PID {}: {}",
-                            new_pid,
-                            e
-                        );
-                        None
-                    }
-                },
-                Some(new_pid) => {
-                    log::trace!("New PID found to be {}", new_pid);
-                    Some(new_pid)
-                }
-                _ => {
-                    log::trace!("Could not find PID matching the criteria.");
-                    None
-                }
-            };
+            self.pid = self.find_pid(ppid);
+            match self.pid {
+                Some(new_pid) => log::trace!("New PID found to be {}", new_pid),
+                None => log::trace!("Could not find PID matching the criteria."),
+            }
         }
 
         log::trace!("The PID is {:?}", self.pid);
@@ -111,19 +210,30 @@ impl ProcessDetector {
         self.pid
     }
 
-    fn find_pid(&self) -> Option<Pid> {
+    /// Finds the PID of the process matching the detection that is related to the provided parent PID, if any.
+    fn find_pid(&self, ppid: Option<Pid>) -> Option<Pid> {
+        match self.detection {
+            ProcessDetection::Pid(_) | ProcessDetection::PidFile(_, _) => self
+                .find_known_pid()
+                .filter(|pid| self.related_to(ppid, pid)),
+            // the parent needs to be checked while scanning, the first match may be under another parent
+            ProcessDetection::All(ref detections) if !detections.iter().any(is_known) => {
+                self.scan(ppid)
+            }
+            ProcessDetection::All(_) => self
+                .find_known_pid()
+                .filter(|pid| self.related_to(ppid, pid)),
+            ProcessDetection::Cmdline(_)
+            | ProcessDetection::Exe(_)
+            | ProcessDetection::Comm(_)
+            | ProcessDetection::Uid(_)
+            | ProcessDetection::Container(_) => self.scan(ppid),
+        }
+    }
+
+    /// Finds the PID of the process for detections that don't need to scan the whole process list.
+    fn find_known_pid(&self) -> Option<Pid> {
         match self.detection {
-            ProcessDetection::Cmdline(ref regex) => match scan_proc(&regex) {
-                Ok(res) => res,
-                Err(e) => {
-                    log::error!(
-                        "Failed to scan the process list for process matching {}: {}",
-                        regex,
-                        e
-                    );
-                    None
-                }
-            },
             ProcessDetection::Pid(ref pid) => {
                 if *pid == 0 {
                     // special case - PID 0 is mainly useful for specifying PPID of an init-like process
@@ -132,7 +242,7 @@ impl ProcessDetector {
                     Some(Pid::from_raw(*pid))
                 } else {
                     let pid = Pid::from_raw(*pid);
-                    if ProcessDetector::pid_exists(&pid) {
+                    if self.proc.exists(&pid) {
                         log::trace!("The required PID {} found.", pid);
                         Some(pid)
                     } else {
@@ -141,54 +251,126 @@ impl ProcessDetector {
                     }
                 }
             }
-        }
-    }
-
-    fn valid(&self) -> bool {
-        log::trace!("Checking whether the current PID {:?} is valid.", self.pid);
-        match self.pid {
-            Some(pid) => match self.detection {
-                ProcessDetection::Cmdline(ref regex) => {
-                    match parse_cmdline(format!("/proc/{}/cmdline", pid)) {
-                        Ok(cmdline) => {
-                            log::trace!(
-                                "Checking whether the cmdline `{}` matches regex `{:?}`",
-                                cmdline,
-                                regex
-                            );
-                            regex.is_match(&cmdline)
-                        }
-                        Err(e) => {
-                            log::warn!("Failed to detect if process {} is still valid: {}", pid, e);
-                            false
-                        }
-                    }
-                }
-                ProcessDetection::Pid(ref expected_pid) => {
-                    if pid.as_raw() == *expected_pid {
-                        log::trace!("Checking whether the required PID {} exists", expected_pid);
-                        ProcessDetector::pid_exists(&pid)
+            ProcessDetection::PidFile(ref path, _) => match read_pid_file(path) {
+                Ok(pid) => {
+                    if matches(&self.proc, &pid, &self.detection) {
+                        log::trace!("The PID {} from pidfile {:?} found.", pid, path);
+                        Some(pid)
                     } else {
                         log::trace!(
-                            "Current PID {} is different from the required PID {}.",
+                            "The PID {} from pidfile {:?} NOT found or doesn't match.",
                             pid,
-                            expected_pid
+                            path
                         );
-                        false
+                        None
                     }
                 }
+                Err(e) => {
+                    log::debug!("Failed to read the pidfile {:?}: {}", path, e);
+                    None
+                }
             },
-            None => false,
+            ProcessDetection::All(ref detections) => {
+                // if the PID is known upfront, there's no need to scan all the processes
+                let known = detections.iter().find(|d| is_known(d))?;
+                let candidate = ProcessDetector {
+                    detection: known.clone(),
+                    scan_options: self.scan_options,
+                    relation: self.relation,
+                    proc: self.proc.clone(),
+                    pid: None,
+                    parent: None,
+                }
+                .find_known_pid();
+                candidate.filter(|pid| matches(&self.proc, pid, &self.detection))
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks whether the process is related to the provided parent process as required by the configured relation.
+    fn related_to(&self, ppid: Option<Pid>, pid: &Pid) -> bool {
+        let ppid = match ppid {
+            Some(ppid) => ppid,
+            None => return true,
+        };
+
+        let max_depth = match self.relation {
+            Relation::Parent => Some(1),
+            Relation::Ancestor(max_depth) => max_depth,
+        };
+
+        match is_ancestor(&self.proc, &ppid, pid, max_depth) {
+            Ok(yes) => {
+                if !yes {
+                    log::trace!(
+                        "The PID {} is not related to the PPID {} as {:?}.",
+                        pid,
+                        ppid,
+                        self.relation
+                    );
+                }
+                yes
+            }
+            Err(e) => {
+                log::error!("Failed to determine parent process of PID {}: {}", pid, e);
+                false
+            }
+        }
+    }
+
+    fn scan(&self, ppid: Option<Pid>) -> Option<Pid> {
+        let proc = &self.proc;
+        let me = proc.self_pid().unwrap_or_else(getpid);
+        let mut excluded = HashSet::new();
+        excluded.insert(me);
+        if self.scan_options.exclude_ancestors {
+            excluded.extend(ancestors(proc, &me));
+        }
+
+        let exclude_zombies = self.scan_options.exclude_zombies;
+
+        let result = scan_proc(proc, |pid| {
+            if excluded.contains(pid) {
+                log::trace!("Excluding {} from the scan.", pid);
+                return false;
+            }
+
+            if exclude_zombies && is_zombie(proc, pid) {
+                log::trace!("Excluding zombie {} from the scan.", pid);
+                return false;
+            }
+
+            matches(proc, pid, &self.detection) && self.related_to(ppid, pid)
+        });
+
+        match result {
+            Ok(res) => res,
+            Err(e) => {
+                log::error!(
+                    "Failed to scan the process list for process matching {:?}: {}",
+                    self.detection,
+                    e
+                );
+                None
+            }
         }
     }
 
-    fn pid_exists(pid: &Pid) -> bool {
-        Path::new(&format!("/proc/{}", pid)).exists()
+    fn valid(&self) -> bool {
+        log::trace!("Checking whether the current PID {:?} is valid.", self.pid);
+        match self.pid {
+            // the pidfile may have been replaced by a restart of the process, so matching always re-reads it
+            Some(pid) => matches(&self.proc, &pid, &self.detection),
+            None => false,
+        }
     }
 }
 
 impl Bumper {
-    pub fn new(process_tree: Vec<ProcessDetection>, signal: &str) -> Result<Self> {
+    /// Creates a bumper sending the signal sequence to the process found at the end of the process hierarchy.
+    /// The sequence can be a single signal name. See [sequence::parse](sequence::parse) for the syntax.
+    pub fn new(process_tree: Vec<ProcessDetection>, sequence: &str) -> Result<Self> {
         if process_tree.is_empty() {
             return Err(Error::InitError(
                 "At least 1 process detection needs to be defined.".into(),
@@ -196,7 +378,10 @@ impl Bumper {
         }
 
         let first = ProcessDetector {
-            detection: process_tree.get(0).unwrap().clone(),
+            detection: process_tree.first().unwrap().clone(),
+            scan_options: ScanOptions::default(),
+            relation: Relation::default(),
+            proc: ProcFs::default(),
             pid: None,
             parent: None,
         };
@@ -206,21 +391,226 @@ impl Bumper {
             .skip(1)
             .fold(first, |detector, detection| ProcessDetector {
                 detection: detection.clone(),
+                scan_options: ScanOptions::default(),
+                relation: Relation::default(),
+                proc: ProcFs::default(),
                 pid: None,
                 parent: Some(Box::from(detector)),
             });
 
         Ok(Bumper {
-            process_tree: process_tree,
-            signal: Signal::from_str(signal).map_err(|e| Error::InitError(format!("{}", e)))?,
+            process_tree,
+            steps: sequence::parse(sequence).map_err(|e| Error::InitError(format!("{}", e)))?,
+            target: None,
+            pidfd_supported: true,
+            wait_timeout: None,
+            seen: false,
+            rate_limiter: None,
+            deferred: false,
+            waiting: None,
+            run: None,
         })
     }
 
-    pub fn bump(&mut self) -> Result<()> {
+    /// Sets the options used when scanning the process list for any of the processes in the hierarchy.
+    pub fn with_scan_options(mut self, scan_options: ScanOptions) -> Self {
+        self.for_each_detector(|d| d.scan_options = scan_options);
+        self
+    }
+
+    /// Sets how each process in the hierarchy needs to be related to the process before it.
+    pub fn with_relation(mut self, relation: Relation) -> Self {
+        self.for_each_detector(|d| d.relation = relation);
+        self
+    }
+
+    /// Sets the procfs to look for the processes in. This is `/proc` by default.
+    pub fn with_proc(mut self, proc: ProcFs) -> Self {
+        self.for_each_detector(|d| d.proc = proc.clone());
+        self
+    }
+
+    /// Makes the bump wait for the process to appear, up to the provided timeout, if the process hasn't been seen
+    /// yet. This avoids losing the changes if cm-bump starts before the process. The waiting bump is deferred, see
+    /// [bump_deferred](Bumper::bump_deferred).
+    pub fn with_wait_timeout(mut self, timeout: Duration) -> Self {
+        self.wait_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits how often the process can be bumped. The bumps suppressed by the limiter are merged into the next allowed
+    /// one, see [bump_deferred](Bumper::bump_deferred).
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Blocks until the process can be found, for at most the configured wait timeout. Returns true if the process
+    /// has been found. This is meant for the startup, the bumps don't block waiting for the process.
+    pub fn wait_for_target(&mut self) -> bool {
+        let timeout = self.wait_timeout.unwrap_or_default();
+        let start = Instant::now();
+        loop {
+            if self.is_running() {
+                return true;
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return false;
+            }
+
+            std::thread::sleep(std::cmp::min(WAIT_INTERVAL, timeout - elapsed));
+        }
+    }
+
+    fn for_each_detector<F: FnMut(&mut ProcessDetector)>(&mut self, mut f: F) {
+        let mut detector = Some(&mut self.process_tree);
+        while let Some(d) = detector {
+            f(d);
+            detector = d.parent.as_deref_mut();
+        }
+    }
+
+    /// Finds the PID of the configured process, if it is currently running.
+    pub fn target_pid(&mut self) -> Option<Pid> {
+        self.process_tree.pid()
+    }
+
+    /// The procfs the processes are looked up in.
+    pub fn proc(&self) -> &ProcFs {
+        &self.process_tree.proc
+    }
+
+    /// Checks whether the configured process can currently be found.
+    pub fn is_running(&mut self) -> bool {
         match self.process_tree.pid() {
             Some(pid) => {
-                log::debug!("Sending signal {:?} to process {:?}", self.signal, pid);
-                signal::kill(pid, self.signal).map_err(|e| Error::SignalError(format!("{}", e)))
+                self.seen = true;
+                self.pidfd(pid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether a bump has been suppressed by the rate limiter, waits for the process to appear or is still running
+    /// its signal sequence, and is not done yet.
+    pub fn is_deferred(&self) -> bool {
+        self.deferred || self.waiting.is_some() || self.run.is_some()
+    }
+
+    /// Remembers the bump to be done again by [bump_deferred](Bumper::bump_deferred), e.g. after it failed.
+    pub fn defer(&mut self) {
+        self.deferred = true;
+    }
+
+    /// Whether the signal sequence of a bump is running, i.e. it has been started but is not done yet.
+    pub fn is_signalling(&self) -> bool {
+        self.run.is_some()
+    }
+
+    /// Whether the deferred bump can be advanced now, i.e. its signal sequence is running, the process it waits for
+    /// appeared or the wait timed out, or the bump is allowed by the rate limiter.
+    pub fn is_deferred_due(&mut self) -> bool {
+        if self.run.is_some() {
+            return true;
+        }
+
+        if let Some(until) = self.waiting {
+            return self.is_running() || Instant::now() >= until;
+        }
+
+        self.deferred
+            && self
+                .rate_limiter
+                .as_ref()
+                .map(|l| l.ready(Instant::now()))
+                .unwrap_or(true)
+    }
+
+    /// Advances the deferred bump, if it can be advanced by now. Returns true if the bump is done, even if the process
+    /// it waited for didn't appear in time.
+    pub fn bump_deferred(&mut self) -> Result<bool> {
+        if self.run.is_some() {
+            self.advance()?;
+            if self.run.is_some() || !self.deferred {
+                return Ok(self.run.is_none());
+            }
+        }
+
+        if !self.is_deferred_due() {
+            return Ok(false);
+        }
+
+        if self.waiting.take().is_some() {
+            if !self.is_running() {
+                log::warn!(
+                    "The process didn't appear in {:?}. Bump has no effect.",
+                    self.wait_timeout.unwrap_or_default()
+                );
+                return Ok(true);
+            }
+            log::info!("Doing the bump that waited for the process to appear.");
+        } else {
+            log::info!("Doing the deferred bump.");
+        }
+
+        self.bump()?;
+        Ok(!self.is_deferred())
+    }
+
+    /// Bumps the process. If the process hasn't been seen yet and the bump is configured to wait for it, if the rate
+    /// limiter doesn't allow the bump or if the signal sequence of the previous bump is still running, it is only
+    /// remembered to be done later. The signal sequence is started right away but its delays and waits for the
+    /// processes to exit are only advanced by [bump_deferred](Bumper::bump_deferred). Use
+    /// [is_deferred](Bumper::is_deferred) to tell whether the bump is done.
+    pub fn bump(&mut self) -> Result<()> {
+        if self.waiting.is_some() {
+            log::debug!("The bump still waits for the process to appear. Merging into it.");
+            return Ok(());
+        }
+
+        if self.run.is_some() {
+            if !self.deferred {
+                log::info!("The signal sequence of the previous bump is still running. Bumping again after it.");
+            }
+            self.deferred = true;
+            return Ok(());
+        }
+
+        if let (false, Some(timeout)) = (self.seen, self.wait_timeout) {
+            if !self.is_running() {
+                log::info!(
+                    "The process hasn't been seen yet. The bump waits up to {:?} for it to appear.",
+                    timeout
+                );
+                self.waiting = Some(Instant::now() + timeout);
+                return Ok(());
+            }
+        }
+
+        if let Some(ref mut limiter) = self.rate_limiter {
+            if !limiter.acquire(Instant::now()) {
+                if !self.deferred {
+                    log::info!("Bump suppressed by the rate limit. It will be merged into the next allowed one.");
+                }
+                self.deferred = true;
+                return Ok(());
+            }
+        }
+        self.deferred = false;
+
+        let pid = self.process_tree.pid();
+
+        if pid.is_some() {
+            self.seen = true;
+        }
+
+        match pid {
+            Some(pid) => {
+                self.run = Some(Run::new(pid, self.pidfd(pid)));
+                self.advance()
             }
             _ => {
                 log::info!("No process of the configured name found running. Bump has no effect.");
@@ -228,106 +618,677 @@ impl Bumper {
             }
         }
     }
-}
-
-fn scan_proc(proc_cmd: &Regex) -> Result<Option<Pid>> {
-    std::fs::read_dir("/proc")
-        .map_err(|e| proc_error(&e))?
-        .map(|e| {
-            log::trace!("Inspecting {:?}", e);
-            e
-        })
-        .filter_map(|r| r.ok())
-        .filter(|e| {
-            // check if the directory can be parsed as a number - that would be a pid of a process
-            e.path()
-                .file_name()
-                .map(|f| f.to_str().map(|f| f.to_string()))
-                .flatten()
-                .filter(|f| f.parse::<u16>().is_ok())
-                .is_some()
-        })
-        .map(|e| {
-            // now see if the comm of the process is what we're looking for
-            let mut comm_path = e.path();
-            comm_path.push("cmdline");
-            let comm = parse_cmdline(comm_path)?;
 
-            log::trace!(
-                "Checking `{}` with cmdline `{}`",
-                e.file_name().to_string_lossy(),
-                comm
-            );
+    /// Runs the steps of the signal sequence that are due, until the sequence needs to wait or is done. If the
+    /// process exited before the first signal could be sent, e.g. because the cached pidfd refers to a process
+    /// restarted under the same PID, the process is detected again and the sequence restarted once.
+    fn advance(&mut self) -> Result<()> {
+        // the run is put back only if it needs to wait, so that it is dropped when done or failed
+        while let Some(mut run) = self.run.take() {
+            let now = Instant::now();
 
-            if proc_cmd.is_match(&comm) {
-                log::trace!("Matched {}.", comm);
-                e.file_name()
-                    .to_str()
-                    .map(|f| {
-                        f.parse::<i32>()
-                            .map(|pid| Some(Pid::from_raw(pid)))
-                            .map_err(|e| proc_error(&e))
-                    })
-                    .unwrap_or(Ok(None))
-            } else {
-                log::trace!("{} doesn't match.", comm);
-                Ok(None)
+            if let Some((pids, until)) = run.exiting.take() {
+                if self.all_exited(&pids) {
+                    log::debug!(
+                        "The processes {:?} exited. Skipping the rest of the sequence.",
+                        pids
+                    );
+                    return Ok(());
+                }
+                if now < until {
+                    run.exiting = Some((pids, until));
+                    self.run = Some(run);
+                    return Ok(());
+                }
+                log::info!(
+                    "The processes {:?} didn't exit in time after {:?}. Escalating.",
+                    pids,
+                    self.steps[run.step - 1].signal
+                );
             }
-        })
-        .filter(|r| if let Ok(Some(_)) = r { true } else { false })
-        .next()
-        .unwrap_or(Ok(None))
-}
 
-fn proc_error(e: &dyn ToString) -> Error {
-    Error::ProcError(e.to_string())
-}
+            let step = match self.steps.get(run.step) {
+                Some(step) => step.clone(),
+                None => return Ok(()),
+            };
 
-fn parse_cmdline<P: AsRef<Path>>(e: P) -> Result<String> {
-    let bytes = fs::read(e).map_err(|e| proc_error(&e))?;
-    // the cmdline is \0 separated, so we need to convert
-    let cmdline =
-        bytes
-            .split(|b| *b == 0)
-            .map(|a| str::from_utf8(a))
-            .fold(String::new(), |mut acc, s| {
-                if !acc.is_empty() {
-                    acc.push_str(" ");
+            let due = *run.due.get_or_insert_with(|| {
+                if step.delay > Duration::from_secs(0) {
+                    log::debug!("Waiting {:?} before sending {:?}.", step.delay, step.signal);
                 }
-                acc.push_str(s.unwrap_or(""));
-                acc
+                now + step.delay
             });
-    Ok(cmdline.trim().to_string())
-}
+            if now < due {
+                self.run = Some(run);
+                return Ok(());
+            }
 
-fn is_parent(ppid: &Pid, new_pid: &Pid) -> Result<bool> {
-    let stat =
-        fs::read_to_string(format!("/proc/{}/stat", *new_pid)).map_err(|e| proc_error(&e))?;
-    match stat.rfind(") ") {
-        Some(last_paren) => {
-            let mut splits = stat.split_at(last_paren + 2).1.split(" ");
-            splits.next();
-            let found_ppid = splits.next();
-            match found_ppid {
-                Some(found_ppid) => match found_ppid.parse::<i32>() {
-                    Ok(found_ppid) => Ok(ppid.as_raw() == found_ppid),
+            let targets = match step.target {
+                Target::Process => match self.process_tree.pid() {
+                    Some(pid) => vec![(pid, self.pidfd(pid))],
+                    None => vec![],
+                },
+                Target::Initial => vec![(run.initial, run.initial_pidfd.clone())],
+                Target::Children => match self.process_tree.proc.children(&run.initial) {
+                    Ok(children) => children.into_iter().map(|pid| (pid, None)).collect(),
                     Err(e) => {
                         log::error!(
-                            "Could not parse ppid {} as a number, weird: {}",
-                            found_ppid,
+                            "Failed to list the children of process {}: {}",
+                            run.initial,
                             e
                         );
-                        Ok(false)
+                        vec![]
                     }
                 },
-                None => Ok(false),
+            };
+
+            if targets.is_empty() {
+                log::info!("No process to send {:?} to. Skipping.", step.signal);
             }
+
+            for (pid, pidfd) in &targets {
+                log::debug!("Sending signal {:?} to process {:?}", step.signal, pid);
+                if self.send_signal(*pid, step.signal, pidfd.as_deref())? {
+                    run.signalled = true;
+                } else if run.signalled {
+                    log::info!("The process {} exited before it could be signalled.", pid);
+                } else if run.retried {
+                    log::info!(
+                        "The process {} exited before it could be signalled. Bump has no effect.",
+                        pid
+                    );
+                    return Ok(());
+                } else {
+                    log::info!(
+                        "The process {} exited before it could be signalled. Detecting it again.",
+                        pid
+                    );
+                    return match self.process_tree.pid() {
+                        Some(pid) => {
+                            let mut retry = Run::new(pid, self.pidfd(pid));
+                            retry.retried = true;
+                            self.run = Some(retry);
+                            self.advance()
+                        }
+                        None => {
+                            log::info!(
+                                "No process of the configured name found running. Bump has no effect."
+                            );
+                            Ok(())
+                        }
+                    };
+                }
+            }
+
+            if let Some(timeout) = step.wait_for_exit {
+                if !targets.is_empty() {
+                    let pids = targets.iter().map(|(pid, _)| *pid).collect();
+                    run.exiting = Some((pids, now + timeout));
+                }
+            }
+
+            run.step += 1;
+            run.due = None;
+            self.run = Some(run);
         }
-        None => Ok(false),
+
+        Ok(())
+    }
+
+    /// Sends the signal through the pidfd of the process if available, falling back to `kill` otherwise. Returns
+    /// false if the process of the pidfd has exited, in which case the pidfd is no longer cached.
+    fn send_signal(&mut self, pid: Pid, signal: Signal, pidfd: Option<&PidFd>) -> Result<bool> {
+        if let Some(pidfd) = pidfd {
+            return match pidfd.send_signal(signal) {
+                Ok(_) => Ok(true),
+                Err(ref e) if pidfd::is_gone(e) => {
+                    if self.target.as_ref().map(|t| t.pid()) == Some(pid) {
+                        self.target = None;
+                    }
+                    Ok(false)
+                }
+                Err(e) => Err(Error::SignalError(format!("{}", e))),
+            };
+        }
+
+        signal::kill(pid, signal)
+            .map(|_| true)
+            .map_err(|e| Error::SignalError(format!("{}", e)))
+    }
+
+    /// Checks whether all the processes exited.
+    fn all_exited(&self, pids: &[Pid]) -> bool {
+        let proc = &self.process_tree.proc;
+        // the processes are not our children, so they stay zombies until reaped by their parents
+        pids.iter()
+            .all(|pid| !proc.exists(pid) || is_zombie(proc, pid))
+    }
+
+    /// Returns the pidfd of the process, opening a new one if the process changed since the last time.
+    fn pidfd(&mut self, pid: Pid) -> Option<Arc<PidFd>> {
+        if !self.pidfd_supported {
+            return None;
+        }
+
+        if let Some(ref target) = self.target {
+            if target.pid() == pid {
+                return Some(target.clone());
+            }
+        }
+
+        self.target = match PidFd::open(pid) {
+            // the process could have exited and its PID been reused between the detection and opening the pidfd,
+            // so we need to check that the pidfd refers to the process we detected.
+            Ok(Some(pidfd)) if self.process_tree.valid() => {
+                log::debug!("Opened pidfd for process {}.", pid);
+                Some(Arc::new(pidfd))
+            }
+            Ok(Some(_)) => {
+                log::debug!(
+                    "The process {} changed while opening its pidfd. Ignoring.",
+                    pid
+                );
+                None
+            }
+            Ok(None) => {
+                log::info!("The kernel doesn't support pidfds. Falling back to signalling by PID.");
+                self.pidfd_supported = false;
+                None
+            }
+            Err(e) => {
+                log::warn!("Failed to open pidfd for process {}: {}", pid, e);
+                None
+            }
+        };
+
+        self.target.clone()
     }
 }
 
+fn scan_proc<F: Fn(&Pid) -> bool>(proc: &ProcFs, predicate: F) -> Result<Option<Pid>> {
+    Ok(proc
+        .pids()
+        .map_err(|e| proc_error(&e))?
+        .into_iter()
+        .find(|pid| {
+            // now see if the process is what we're looking for
+            if predicate(pid) {
+                log::trace!("Matched {}.", pid);
+                true
+            } else {
+                log::trace!("{} doesn't match.", pid);
+                false
+            }
+        }))
+}
+
+/// Whether the detection determines the PID without scanning the process list.
+fn is_known(detection: &ProcessDetection) -> bool {
+    matches!(
+        detection,
+        ProcessDetection::Pid(_) | ProcessDetection::PidFile(_, _)
+    )
+}
+
+/// Checks whether the process with the provided PID matches the detection.
+fn matches(proc: &ProcFs, pid: &Pid, detection: &ProcessDetection) -> bool {
+    match detection {
+        ProcessDetection::Cmdline(ref regex) => cmdline_matches(proc, pid, regex),
+        ProcessDetection::Pid(ref expected_pid) => {
+            if pid.as_raw() == *expected_pid {
+                log::trace!("Checking whether the required PID {} exists", expected_pid);
+                proc.exists(pid)
+            } else {
+                log::trace!(
+                    "Current PID {} is different from the required PID {}.",
+                    pid,
+                    expected_pid
+                );
+                false
+            }
+        }
+        ProcessDetection::PidFile(ref path, ref regex) => {
+            read_pid_file(path).ok() == Some(*pid)
+                && proc.exists(pid)
+                && regex
+                    .as_ref()
+                    .map(|r| cmdline_matches(proc, pid, r))
+                    .unwrap_or(true)
+        }
+        ProcessDetection::Exe(ref expected) => match proc.exe(pid) {
+            Ok(exe) => {
+                log::trace!("Checking whether the exe {:?} is {:?}", exe, expected);
+                exe == *expected
+            }
+            Err(e) => {
+                log::trace!("Failed to read the exe of process {}: {}", pid, e);
+                false
+            }
+        },
+        ProcessDetection::Comm(ref expected) => match proc.comm(pid) {
+            Ok(comm) => {
+                log::trace!("Checking whether the comm `{}` is `{}`", comm, expected);
+                comm == *expected
+            }
+            Err(e) => {
+                log::trace!("Failed to read the comm of process {}: {}", pid, e);
+                false
+            }
+        },
+        ProcessDetection::Container(ref container) => match proc.cgroup(pid) {
+            Ok(cgroup) => container.contains(&cgroup),
+            Err(e) => {
+                log::trace!("Failed to read the cgroup of process {}: {}", pid, e);
+                false
+            }
+        },
+        ProcessDetection::Uid(ref expected) => match proc.uid(pid) {
+            Ok(uid) => uid == *expected,
+            Err(e) => {
+                log::trace!("Failed to read the owner of process {}: {}", pid, e);
+                false
+            }
+        },
+        ProcessDetection::All(ref detections) => detections.iter().all(|d| matches(proc, pid, d)),
+    }
+}
+
+fn cmdline_matches(proc: &ProcFs, pid: &Pid, regex: &Regex) -> bool {
+    match proc.cmdline(pid) {
+        Ok(cmdline) => {
+            log::trace!(
+                "Checking whether the cmdline `{}` matches regex `{:?}`",
+                cmdline,
+                regex
+            );
+            regex.is_match(&cmdline)
+        }
+        Err(e) => {
+            log::warn!("Failed to detect if process {} is still valid: {}", pid, e);
+            false
+        }
+    }
+}
+
+fn read_pid_file<P: AsRef<Path>>(path: P) -> Result<Pid> {
+    let content = fs::read_to_string(path).map_err(|e| proc_error(&e))?;
+    match content.trim().parse::<i32>() {
+        Ok(pid) if pid > 0 => Ok(Pid::from_raw(pid)),
+        Ok(pid) => Err(Error::ProcError(format!("Invalid PID {} in pidfile", pid))),
+        Err(e) => Err(proc_error(&e)),
+    }
+}
+
+fn proc_error(e: &dyn ToString) -> Error {
+    Error::ProcError(e.to_string())
+}
+
+/// Walks up the process tree from the process looking for the ancestor, at most `max_depth` levels up if specified.
+fn is_ancestor(proc: &ProcFs, ancestor: &Pid, pid: &Pid, max_depth: Option<u32>) -> Result<bool> {
+    let mut current = *pid;
+    let mut depth = 0;
+    let mut visited = HashSet::new();
+    while max_depth.map(|max| depth < max).unwrap_or(true) && visited.insert(current) {
+        let ppid = match proc.stat(&current).map_err(|e| proc_error(&e))? {
+            Some(stat) => Pid::from_raw(stat.ppid),
+            None => return Ok(false),
+        };
+
+        depth += 1;
+
+        if ppid == *ancestor {
+            return Ok(true);
+        }
+
+        if ppid.as_raw() <= 0 {
+            break;
+        }
+
+        current = ppid;
+    }
+
+    Ok(false)
+}
+
+fn is_zombie(proc: &ProcFs, pid: &Pid) -> bool {
+    matches!(proc.stat(pid), Ok(Some(Stat { state: 'Z', .. })))
+}
+
+/// Collects the parent, grand-parent, etc. of the process up to the root of the process tree.
+fn ancestors(proc: &ProcFs, pid: &Pid) -> Vec<Pid> {
+    let mut ret = vec![];
+    let mut current = *pid;
+    while let Ok(Some(stat)) = proc.stat(&current) {
+        if stat.ppid <= 0 || ret.contains(&Pid::from_raw(stat.ppid)) {
+            break;
+        }
+        current = Pid::from_raw(stat.ppid);
+        ret.push(current);
+    }
+    ret
+}
+
+#[cfg(test)]
 mod test {
+    use super::{Bumper, ProcFs, ProcessDetection, Relation};
+    use nix::unistd::Pid;
+    use regex::Regex;
+    use std::path::Path;
+
+    fn fake_process(root: &Path, pid: i32, ppid: i32, cmdline: &str) {
+        let dir = root.join(pid.to_string());
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("cmdline"), cmdline.replace(' ', "\0")).unwrap();
+        let comm = super::truncate(cmdline.split(' ').next().unwrap(), super::COMM_MAX_LEN);
+        std::fs::write(dir.join("comm"), format!("{}\n", comm)).unwrap();
+        std::fs::write(
+            dir.join("stat"),
+            format!("{} ({}) S {} {} 0 0", pid, comm, ppid, pid),
+        )
+        .unwrap();
+    }
+
+    fn cmdline(regex: &str) -> ProcessDetection {
+        ProcessDetection::Cmdline(Regex::new(regex).unwrap())
+    }
+
+    #[test]
+    fn test_hierarchy_in_fake_proc() {
+        let root = tempfile::tempdir().unwrap();
+        fake_process(root.path(), 1, 0, "tini -- nginx");
+        fake_process(root.path(), 70000, 1, "nginx: master process");
+        fake_process(root.path(), 70001, 70000, "nginx: worker process");
+        fake_process(root.path(), 80000, 1, "sh -c other");
+        fake_process(root.path(), 80001, 80000, "nginx: worker process");
+
+        let proc = ProcFs::new(root.path());
+        let is_parent = |ppid, pid| {
+            super::is_ancestor(&proc, &Pid::from_raw(ppid), &Pid::from_raw(pid), Some(1)).unwrap()
+        };
+        assert!(is_parent(70000, 70001));
+        assert!(!is_parent(80000, 70001));
+        assert!(!is_parent(1, 70001));
+
+        let mut bumper = Bumper::new(vec![cmdline("^tini"), cmdline("^nginx: master")], "SIGHUP")
+            .unwrap()
+            .with_proc(proc.clone());
+        assert_eq!(Some(Pid::from_raw(70000)), bumper.process_tree.pid());
+
+        // both workers match, only the one with the right parent should be found
+        let mut bumper = Bumper::new(vec![cmdline("^sh"), cmdline("^nginx: worker")], "SIGHUP")
+            .unwrap()
+            .with_proc(proc.clone());
+        assert_eq!(Some(Pid::from_raw(80001)), bumper.process_tree.pid());
+
+        let mut bumper = Bumper::new(
+            vec![cmdline("^nginx: master"), cmdline("^nginx: worker")],
+            "SIGHUP",
+        )
+        .unwrap()
+        .with_proc(proc);
+        assert_eq!(Some(Pid::from_raw(70001)), bumper.process_tree.pid());
+    }
+
+    #[test]
+    fn test_ancestor_in_fake_proc() {
+        let root = tempfile::tempdir().unwrap();
+        fake_process(root.path(), 1, 0, "tini -- sh");
+        fake_process(root.path(), 10, 1, "sh -c wrapper");
+        fake_process(root.path(), 20, 10, "sh -c payload");
+        fake_process(root.path(), 30, 20, "payload --serve");
+
+        let proc = ProcFs::new(root.path());
+        let detections = vec![cmdline("^tini"), cmdline("^payload")];
+
+        let mut bumper = Bumper::new(detections.clone(), "SIGHUP")
+            .unwrap()
+            .with_proc(proc.clone());
+        assert_eq!(None, bumper.process_tree.pid());
+
+        let mut bumper = Bumper::new(detections.clone(), "SIGHUP")
+            .unwrap()
+            .with_relation(Relation::Ancestor(Some(2)))
+            .with_proc(proc.clone());
+        assert_eq!(None, bumper.process_tree.pid());
+
+        let mut bumper = Bumper::new(detections.clone(), "SIGHUP")
+            .unwrap()
+            .with_relation(Relation::Ancestor(Some(3)))
+            .with_proc(proc.clone());
+        assert_eq!(Some(Pid::from_raw(30)), bumper.process_tree.pid());
+
+        let mut bumper = Bumper::new(detections, "SIGHUP")
+            .unwrap()
+            .with_relation(Relation::Ancestor(None))
+            .with_proc(proc);
+        assert_eq!(Some(Pid::from_raw(30)), bumper.process_tree.pid());
+    }
+
+    #[test]
+    fn test_validity_in_fake_proc() {
+        let root = tempfile::tempdir().unwrap();
+        fake_process(root.path(), 100, 1, "haproxy -f /etc/haproxy");
+
+        let mut bumper = Bumper::new(vec![cmdline("^haproxy")], "SIGHUP")
+            .unwrap()
+            .with_proc(ProcFs::new(root.path()));
+        assert_eq!(Some(Pid::from_raw(100)), bumper.process_tree.pid());
+        assert!(bumper.process_tree.valid());
+
+        // the process restarted with a new PID
+        std::fs::remove_dir_all(root.path().join("100")).unwrap();
+        fake_process(root.path(), 200, 1, "haproxy -f /etc/haproxy");
+        assert!(!bumper.process_tree.valid());
+        assert_eq!(Some(Pid::from_raw(200)), bumper.process_tree.pid());
+    }
+
+    #[test]
+    fn test_wait_for_target() {
+        let root = tempfile::tempdir().unwrap();
+        let mut bumper = Bumper::new(vec![cmdline("^late")], "SIGHUP")
+            .unwrap()
+            .with_wait_timeout(std::time::Duration::from_millis(100))
+            .with_proc(ProcFs::new(root.path()));
+
+        assert!(!bumper.wait_for_target());
+
+        let late_root = root.path().to_owned();
+        let starter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            fake_process(&late_root, 4242, 1, "late --start");
+        });
+
+        bumper = bumper.with_wait_timeout(std::time::Duration::from_secs(10));
+        assert!(bumper.wait_for_target());
+        starter.join().unwrap();
+    }
+
+    #[test]
+    fn test_bump_waits_for_target() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let mut bumper = Bumper::new(vec![cmdline("^late")], "SIGTERM")
+            .unwrap()
+            .with_wait_timeout(std::time::Duration::from_secs(10))
+            .with_proc(ProcFs::new(root.path()));
+
+        bumper.bump().unwrap();
+        assert!(bumper.is_deferred());
+        assert!(!bumper.is_deferred_due());
+        assert!(!bumper.bump_deferred().unwrap());
+
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        fake_process(root.path(), child.id() as i32, 1, "late --start");
+
+        assert!(bumper.is_deferred_due());
+        assert!(bumper.bump_deferred().unwrap());
+        assert!(!bumper.is_deferred());
+
+        let status = child.wait().unwrap();
+        assert_eq!(
+            Some(nix::sys::signal::Signal::SIGTERM as i32),
+            status.signal()
+        );
+    }
+
+    #[test]
+    fn test_bump_waiting_for_target_times_out() {
+        let root = tempfile::tempdir().unwrap();
+        let mut bumper = Bumper::new(vec![cmdline("^late")], "SIGTERM")
+            .unwrap()
+            .with_wait_timeout(std::time::Duration::from_millis(0))
+            .with_proc(ProcFs::new(root.path()));
+
+        bumper.bump().unwrap();
+        assert!(bumper.is_deferred());
+        assert!(bumper.bump_deferred().unwrap());
+        assert!(!bumper.is_deferred());
+    }
+
+    #[test]
+    fn test_escalation_when_process_does_not_exit() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg("trap '' TERM; sleep 30")
+            .spawn()
+            .unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+
+        // give the shell the time to ignore SIGTERM
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        let mut bumper = Bumper::new(
+            vec![ProcessDetection::Pid(pid.as_raw())],
+            "SIGTERM,wait=300ms;SIGKILL",
+        )
+        .unwrap();
+        bumper.bump().unwrap();
+
+        // the bump doesn't block waiting for the process to exit, the rest of the sequence is done by the ticks
+        assert!(bumper.is_deferred());
+        assert!(child.try_wait().unwrap().is_none());
+        while !bumper.bump_deferred().unwrap() {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert!(!bumper.is_deferred());
+
+        let status = child.wait().unwrap();
+        assert_eq!(
+            Some(nix::sys::signal::Signal::SIGKILL as i32),
+            status.signal()
+        );
+    }
+
+    #[test]
+    fn test_all_detections_must_match() {
+        use super::matches;
+
+        let root = tempfile::tempdir().unwrap();
+        fake_process(root.path(), 100, 1, "nginx-with-a-long-name -g daemon");
+        let exe = root.path().join("nginx");
+        std::os::unix::fs::symlink(&exe, root.path().join("100/exe")).unwrap();
+        let uid = nix::unistd::geteuid().as_raw();
+
+        let proc = ProcFs::new(root.path());
+        let pid = Pid::from_raw(100);
+        let mut detections = vec![
+            ProcessDetection::Exe(exe),
+            ProcessDetection::Comm("nginx-with-a-lo".into()),
+            ProcessDetection::Uid(uid),
+        ];
+        assert!(matches(
+            &proc,
+            &pid,
+            &ProcessDetection::All(detections.clone())
+        ));
+
+        detections.push(ProcessDetection::Comm("definitely-not".into()));
+        assert!(!matches(&proc, &pid, &ProcessDetection::All(detections)));
+    }
+
+    #[test]
+    fn test_all_detections_under_parent_in_fake_proc() {
+        let root = tempfile::tempdir().unwrap();
+        fake_process(root.path(), 1, 0, "tini -- sh");
+        fake_process(root.path(), 10, 1, "sh -c other");
+        fake_process(root.path(), 11, 10, "nginx -g daemon");
+        fake_process(root.path(), 20, 1, "supervisor");
+        fake_process(root.path(), 21, 20, "nginx -g daemon");
+        fake_process(root.path(), 30, 1, "sh -c another");
+        fake_process(root.path(), 31, 30, "nginx -g daemon");
+
+        // both nginx processes match, only the one under the supervisor should be found
+        let mut bumper = Bumper::new(
+            vec![
+                cmdline("^supervisor"),
+                ProcessDetection::All(vec![
+                    cmdline("^nginx"),
+                    ProcessDetection::Comm("nginx".into()),
+                ]),
+            ],
+            "SIGHUP",
+        )
+        .unwrap()
+        .with_proc(ProcFs::new(root.path()));
+        assert_eq!(Some(Pid::from_raw(21)), bumper.process_tree.pid());
+    }
+
+    #[test]
+    fn test_comm_parsing() {
+        assert_eq!("nginx", super::parse_comm("nginx").unwrap());
+        assert_eq!(
+            "nginx-with-a-lo",
+            super::parse_comm("nginx-with-a-lo").unwrap()
+        );
+        assert!(super::parse_comm("nginx-with-a-long-name").is_err());
+        assert!(super::parse_comm("").is_err());
+    }
+
+    #[test]
+    fn test_pid_file_parsing() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("nginx.pid");
+
+        std::fs::write(&pid_file, "4242\n").unwrap();
+        assert_eq!(
+            super::Pid::from_raw(4242),
+            super::read_pid_file(&pid_file).unwrap()
+        );
+
+        std::fs::write(&pid_file, "").unwrap();
+        assert!(super::read_pid_file(&pid_file).is_err());
+
+        std::fs::write(&pid_file, "-1").unwrap();
+        assert!(super::read_pid_file(&pid_file).is_err());
+    }
+
+    #[test]
+    fn test_own_ancestors() {
+        let me = nix::unistd::getpid();
+        let ancestors = super::ancestors(&super::ProcFs::default(), &me);
+        assert_eq!(Some(&nix::unistd::getppid()), ancestors.first());
+        assert!(!ancestors.contains(&me));
+    }
+
+    #[test]
+    fn test_own_process_never_scanned() {
+        // our own process matches these, but we should never find ourselves
+        let exe = std::env::current_exe().unwrap();
+        let cmdline = Regex::new(&format!("^{}", regex::escape(&exe.to_string_lossy()))).unwrap();
+
+        let mut bumper = Bumper::new(vec![ProcessDetection::Exe(exe)], "SIGHUP").unwrap();
+        assert!(!bumper.is_running());
+
+        let mut bumper = Bumper::new(vec![ProcessDetection::Cmdline(cmdline)], "SIGHUP").unwrap();
+        assert!(!bumper.is_running());
+    }
+
     #[test]
     fn test_stat_parsing() {
         // an executable with a ')' in its name... yuck!
@@ -341,5 +1302,14 @@ mod test {
                 println!("rfind failed.");
             }
         }
+
+        assert_eq!(
+            Some(super::Stat {
+                state: 'S',
+                ppid: 135114,
+                start_time: Some(3568036),
+            }),
+            crate::procfs::parse_stat(stat)
+        );
     }
 }
@@ -0,0 +1,164 @@
+use k8s_openapi::api::core::v1::Pod;
+use kube::{api::Api, Client};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How often the IDs of the containers are refreshed from the pod status.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A container in the pod cm-bump is running in. The ID of the container is only known once the container has been
+/// started and changes each time the container restarts, so it is kept up to date by the [track](track) task.
+#[derive(Debug, Clone)]
+pub struct ContainerRef {
+    name: String,
+    id: Arc<RwLock<Option<String>>>,
+}
+
+impl ContainerRef {
+    pub fn new(name: &str) -> Self {
+        ContainerRef {
+            name: name.to_owned(),
+            id: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The ID of the container, if it is already known.
+    pub fn id(&self) -> Option<String> {
+        self.id.read().ok().and_then(|id| id.clone())
+    }
+
+    fn set_id(&self, id: Option<String>) {
+        if let Ok(mut current) = self.id.write() {
+            if *current != id {
+                log::info!(
+                    "The ID of container `{}` changed from {:?} to {:?}.",
+                    self.name,
+                    *current,
+                    id
+                );
+                *current = id;
+            }
+        }
+    }
+
+    /// Checks whether the contents of `/proc/<pid>/cgroup` place the process in this container.
+    pub fn contains(&self, cgroup: &str) -> bool {
+        match self.id() {
+            Some(id) => cgroup_contains(cgroup, &id),
+            None => {
+                log::trace!("The ID of container `{}` is not known yet.", self.name);
+                false
+            }
+        }
+    }
+}
+
+/// Checks whether any of the cgroups of a process is the one of the container. Both cgroup v1 and v2 paths, as
+/// created by the common container runtimes, have a segment that is the container ID, optionally prefixed by the
+/// runtime and suffixed by `.scope`, e.g. `/kubepods/burstable/pod<uid>/<id>` or
+/// `/kubepods.slice/.../cri-containerd-<id>.scope`.
+fn cgroup_contains(cgroup: &str, id: &str) -> bool {
+    !id.is_empty()
+        && cgroup
+            .lines()
+            .filter_map(|l| l.splitn(3, ':').nth(2))
+            .flat_map(|path| path.split('/'))
+            .any(|segment| is_container_segment(segment, id))
+}
+
+/// Checks whether the segment of a cgroup path is the container ID, e.g. `<id>`, `docker-<id>.scope` or
+/// `crio-<id>`.
+fn is_container_segment(segment: &str, id: &str) -> bool {
+    let segment = segment.trim_end_matches(".scope");
+    segment == id
+        || segment
+            .strip_suffix(id)
+            .map(|prefix| prefix.ends_with('-'))
+            .unwrap_or(false)
+}
+
+/// Strips the runtime prefix, e.g. `containerd://`, from the container ID reported in the pod status.
+fn parse_container_id(id: &str) -> &str {
+    match id.find("://") {
+        Some(idx) => &id[idx + 3..],
+        None => id,
+    }
+}
+
+/// Keeps the IDs of the containers up to date with the status of the pod.
+pub async fn track(
+    client: Client,
+    namespace: String,
+    pod_name: String,
+    containers: Vec<ContainerRef>,
+) {
+    let pods: Api<Pod> = Api::namespaced(client, &namespace);
+
+    loop {
+        match pods.get(&pod_name).await {
+            Ok(pod) => {
+                let statuses = pod
+                    .status
+                    .and_then(|s| s.container_statuses)
+                    .unwrap_or_default();
+
+                for container in &containers {
+                    let id = statuses
+                        .iter()
+                        .find(|s| s.name == container.name)
+                        .and_then(|s| s.container_id.as_ref())
+                        .map(|id| parse_container_id(id).to_owned());
+
+                    if id.is_none() {
+                        log::debug!(
+                            "Container `{}` not found running in pod `{}`.",
+                            container.name,
+                            pod_name
+                        );
+                    }
+
+                    container.set_id(id);
+                }
+            }
+            Err(e) => log::warn!("Failed to read the status of pod `{}`: {}", pod_name, e),
+        }
+
+        tokio::time::delay_for(REFRESH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cgroup_matching() {
+        let id = "0a1b2c3d4e5f";
+        let v1 = "12:memory:/kubepods/burstable/pod1234/0a1b2c3d4e5f\n1:name=systemd:/kubepods/burstable/pod1234/0a1b2c3d4e5f\n";
+        let v2 = "0::/kubepods.slice/kubepods-pod1234.slice/cri-containerd-0a1b2c3d4e5f.scope\n";
+        let other = "0::/kubepods.slice/kubepods-pod1234.slice/cri-containerd-ffffffffffff.scope\n";
+        // the ID of the container is only a part of the ID of another one
+        let longer =
+            "0::/kubepods.slice/kubepods-pod1234.slice/cri-containerd-0a1b2c3d4e5f6789.scope\n";
+        let prefixed = "12:memory:/kubepods/burstable/pod1234/ff0a1b2c3d4e5f\n";
+
+        assert!(cgroup_contains(v1, id));
+        assert!(cgroup_contains(v2, id));
+        assert!(!cgroup_contains(other, id));
+        assert!(!cgroup_contains(longer, id));
+        assert!(!cgroup_contains(prefixed, id));
+        assert!(!cgroup_contains(v2, ""));
+
+        assert_eq!(id, parse_container_id("containerd://0a1b2c3d4e5f"));
+        assert_eq!(id, parse_container_id("0a1b2c3d4e5f"));
+
+        let container = ContainerRef::new("nginx");
+        assert!(!container.contains(v2));
+        container.set_id(Some(id.into()));
+        assert!(container.contains(v2));
+    }
+}
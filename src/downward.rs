@@ -0,0 +1,223 @@
+use super::operator::{self, key};
+use super::source::{Event, EventSource};
+use super::template::{self, Renderer};
+use futures::future::BoxFuture;
+use k8s_openapi::api::core::v1::ConfigMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often the Downward API files referenced from the templates are checked for changes. The kubelet updates the
+/// files with the labels and annotations of the pod in its sync period, so there is no point in checking more often.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Passes through the events about the config maps, checking the Downward API files the config map templates refer to
+/// for changes, e.g. after the labels or the annotations of the pod have been updated. A change of a file results in
+/// a modification of the config maps referring to it, so that they are rendered again.
+pub struct DownwardApiSource<Src> {
+    inner: Src,
+    dir: Option<PathBuf>,
+    interval: Duration,
+    next_poll: Option<Instant>,
+    /// The config maps referring to the Downward API files, with the names of the referenced files, by the keys of
+    /// the config maps.
+    config_maps: BTreeMap<String, (ConfigMap, BTreeSet<String>)>,
+    /// The contents of the referenced files as last read, `None` if a file couldn't be read.
+    contents: BTreeMap<String, Option<String>>,
+    pending: VecDeque<Event<ConfigMap>>,
+}
+
+impl<Src: EventSource<ConfigMap> + Send> DownwardApiSource<Src> {
+    /// Checks the files in the directory, if any, in the provided interval. Without a directory, the events are only
+    /// passed through.
+    pub fn new(inner: Src, dir: Option<PathBuf>, interval: Duration) -> Self {
+        DownwardApiSource {
+            inner,
+            dir,
+            interval,
+            next_poll: None,
+            config_maps: BTreeMap::new(),
+            contents: BTreeMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Updates the references to the Downward API files.
+    fn track(&mut self, ev: &Event<ConfigMap>) {
+        match ev {
+            Event::Added(cm) | Event::Modified(cm) => self.track_config_map(cm),
+            Event::Deleted(cm) => {
+                self.config_maps.remove(&key(cm));
+            }
+            Event::Resync(namespace, cms) => {
+                let prefix = namespace.as_ref().map(|ns| format!("{}/", ns));
+                self.config_maps.retain(|k, _| match prefix {
+                    Some(ref prefix) => !k.starts_with(prefix),
+                    None => false,
+                });
+                for cm in cms {
+                    self.track_config_map(cm);
+                }
+            }
+        }
+    }
+
+    fn track_config_map(&mut self, cm: &ConfigMap) {
+        let cm_key = key(cm);
+        let enabled = cm
+            .metadata
+            .as_ref()
+            .and_then(|m| m.annotations.as_ref())
+            .map(Renderer::is_enabled)
+            .unwrap_or(false);
+
+        let files: BTreeSet<String> = cm
+            .data
+            .iter()
+            .flat_map(|data| data.values())
+            .flat_map(|v| template::pod_references(v))
+            .collect();
+        if self.dir.is_none() || !enabled || files.is_empty() {
+            self.config_maps.remove(&cm_key);
+            return;
+        }
+
+        // the config map is about to be rendered with what the files contain now
+        for file in &files {
+            if !self.contents.contains_key(file) {
+                let content = self.read(file);
+                self.contents.insert(file.clone(), content);
+            }
+        }
+        self.config_maps.insert(cm_key, (cm.clone(), files));
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        let dir = self.dir.as_ref()?;
+        std::fs::read_to_string(dir.join(file)).ok()
+    }
+
+    /// Reads the referenced files again and queues the modifications of the config maps referring to the changed ones.
+    fn poll(&mut self) {
+        let referenced: BTreeSet<String> = self
+            .config_maps
+            .values()
+            .flat_map(|(_, files)| files.iter().cloned())
+            .collect();
+        self.contents.retain(|file, _| referenced.contains(file));
+
+        let mut changed = BTreeSet::new();
+        for file in referenced {
+            let content = self.read(&file);
+            if self.contents.get(&file) != Some(&content) {
+                log::info!("Downward API file `{}` changed.", file);
+                self.contents.insert(file.clone(), content);
+                changed.insert(file);
+            }
+        }
+
+        for (cm, files) in self.config_maps.values() {
+            if !files.is_disjoint(&changed) {
+                log::debug!(
+                    "Rendering config map `{}` again because the Downward API files changed.",
+                    key(cm)
+                );
+                self.pending.push_back(Event::Modified(cm.clone()));
+            }
+        }
+    }
+}
+
+impl<Src: EventSource<ConfigMap> + Send> EventSource<ConfigMap> for DownwardApiSource<Src> {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Event<ConfigMap>, operator::Error>>> {
+        Box::pin(async move {
+            loop {
+                if let Some(ev) = self.pending.pop_front() {
+                    return Some(Ok(ev));
+                }
+
+                // the deadline survives the future being dropped, so the polls keep their pace
+                let interval = self.interval;
+                let at = *self
+                    .next_poll
+                    .get_or_insert_with(|| Instant::now() + interval);
+
+                tokio::select! {
+                    ev = self.inner.next() => match ev {
+                        Some(Ok(ev)) => {
+                            self.track(&ev);
+                            return Some(Ok(ev));
+                        }
+                        other => return other,
+                    },
+                    _ = tokio::time::delay_until(at) => {
+                        self.next_poll = Some(Instant::now() + self.interval);
+                        self.poll();
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use tokio::sync::mpsc;
+
+    fn template(name: &str, value: &str) -> ConfigMap {
+        let mut annotations = BTreeMap::new();
+        annotations.insert(
+            template::TEMPLATE_ANNOTATION.to_string(),
+            "true".to_string(),
+        );
+        let mut data = BTreeMap::new();
+        data.insert("nginx.conf".to_string(), value.to_string());
+        ConfigMap {
+            metadata: Some(ObjectMeta {
+                name: Some(name.into()),
+                namespace: Some("app".into()),
+                annotations: Some(annotations),
+                ..Default::default()
+            }),
+            data: Some(data),
+            ..ConfigMap::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_config_maps_rendered_again_on_file_change() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("labels"), "version=\"1\"\n").unwrap();
+
+        let (events, received) = mpsc::unbounded_channel();
+        let mut source = DownwardApiSource::new(
+            received,
+            Some(dir.path().to_owned()),
+            Duration::from_millis(10),
+        );
+
+        let nginx = template("nginx", "# {{ pod.labels }}");
+        events.send(Ok(Event::Added(nginx.clone()))).unwrap();
+        events
+            .send(Ok(Event::Added(template("other", "# {{ env.HOME }}"))))
+            .unwrap();
+        assert_eq!(
+            Event::Added(nginx.clone()),
+            source.next().await.unwrap().unwrap()
+        );
+        source.next().await.unwrap().unwrap();
+
+        std::fs::write(dir.path().join("labels"), "version=\"2\"\n").unwrap();
+        assert_eq!(
+            Event::Modified(nginx),
+            source.next().await.unwrap().unwrap()
+        );
+
+        // unchanged files don't render the config maps again
+        source.poll();
+        assert!(source.pending.is_empty());
+    }
+}
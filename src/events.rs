@@ -0,0 +1,107 @@
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use kube::{
+    api::{Api, PostParams},
+    Client,
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+const COMPONENT: &str = "cm-bump";
+
+/// The type of the Kubernetes event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
+    Normal,
+    Warning,
+}
+
+/// A notable occurrence that should be reported to the cluster.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub event_type: EventType,
+    pub reason: String,
+    pub message: String,
+}
+
+/// Records notable occurrences as Kubernetes Events on the pod cm-bump is running in. Recording is synchronous and
+/// never blocks, the events are published by the [publish](publish) task.
+#[derive(Debug, Clone, Default)]
+pub struct EventRecorder {
+    sender: Option<UnboundedSender<Notification>>,
+}
+
+impl EventRecorder {
+    /// Creates a new recorder along with the receiving end that should be passed to [publish](publish).
+    pub fn new() -> (Self, UnboundedReceiver<Notification>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (
+            EventRecorder {
+                sender: Some(sender),
+            },
+            receiver,
+        )
+    }
+
+    /// A recorder that doesn't publish anything.
+    pub fn disabled() -> Self {
+        EventRecorder { sender: None }
+    }
+
+    pub fn record(&self, event_type: EventType, reason: &str, message: &str) {
+        if let Some(ref sender) = self.sender {
+            let notification = Notification {
+                event_type,
+                reason: reason.to_owned(),
+                message: message.to_owned(),
+            };
+
+            if let Err(e) = sender.send(notification) {
+                log::warn!("Failed to record event `{}`: {}", reason, e);
+            }
+        }
+    }
+}
+
+/// Publishes the recorded notifications as Events involving the provided pod until all the recorders are dropped.
+pub async fn publish(
+    client: Client,
+    namespace: String,
+    pod_name: String,
+    mut notifications: UnboundedReceiver<Notification>,
+) {
+    let events: Api<Event> = Api::namespaced(client, &namespace);
+
+    while let Some(n) = notifications.recv().await {
+        let now = Time(chrono::Utc::now());
+        let event = Event {
+            metadata: ObjectMeta {
+                generate_name: Some(format!("{}.", pod_name)),
+                namespace: Some(namespace.clone()),
+                ..ObjectMeta::default()
+            },
+            involved_object: ObjectReference {
+                api_version: Some("v1".into()),
+                kind: Some("Pod".into()),
+                name: Some(pod_name.clone()),
+                namespace: Some(namespace.clone()),
+                ..ObjectReference::default()
+            },
+            reason: Some(n.reason.clone()),
+            message: Some(n.message),
+            type_: Some(format!("{:?}", n.event_type)),
+            count: Some(1),
+            first_timestamp: Some(now.clone()),
+            last_timestamp: Some(now),
+            source: Some(EventSource {
+                component: Some(COMPONENT.into()),
+                host: None,
+            }),
+            reporting_component: Some(COMPONENT.into()),
+            ..Event::default()
+        };
+
+        if let Err(e) = events.create(&PostParams::default(), &event).await {
+            log::warn!("Failed to publish event `{}`: {}", n.reason, e);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use super::bumper::Bumper;
+use super::shell;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::Command;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Error)]
+pub enum Error {
+    #[error("Invalid health check configuration: {0}")]
+    ConfigError(String),
+
+    #[error("Health check failed: {0}")]
+    Unhealthy(String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A single check of the health of the bumped process.
+#[derive(Debug, Clone)]
+pub enum HealthCheck {
+    /// The bumped process still needs to be found after the bump.
+    ProcessAlive,
+    /// A GET request to the plain-HTTP URL needs to return a 2xx status.
+    Http(String),
+    /// The command, run by `sh -c`, needs to exit successfully.
+    Command(String),
+}
+
+/// Checks the health of the bumped process some time, the [delay](HealthChecker::delay), after the bump. All the configured checks need to pass for the
+/// process to be considered healthy.
+#[derive(Debug, Clone)]
+pub struct HealthChecker {
+    checks: Vec<HealthCheck>,
+    delay: Duration,
+    timeout: Duration,
+}
+
+impl HealthChecker {
+    pub fn new(checks: Vec<HealthCheck>, delay: Duration) -> Result<Self> {
+        if checks.is_empty() {
+            return Err(Error::ConfigError(
+                "At least 1 health check needs to be defined.".into(),
+            ));
+        }
+
+        for check in &checks {
+            if let HealthCheck::Http(ref url) = check {
+                parse_url(url)?;
+            }
+        }
+
+        Ok(HealthChecker {
+            checks,
+            delay,
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// How long after the bump the checks are to be run.
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Runs all the checks.
+    pub fn check(&self, mut bumper: Option<&mut Bumper>) -> Result<()> {
+        for check in &self.checks {
+            log::debug!("Running health check {:?}", check);
+            match check {
+                HealthCheck::ProcessAlive => match bumper {
+                    Some(ref mut b) => {
+                        if !b.is_running() {
+                            return Err(Error::Unhealthy(
+                                "The bumped process is no longer running.".into(),
+                            ));
+                        }
+                    }
+                    None => {
+                        log::warn!("Process liveness health check configured without a process to bump. Ignoring.")
+                    }
+                },
+                HealthCheck::Http(ref url) => self.check_http(url)?,
+                HealthCheck::Command(ref cmd) => check_command(cmd, self.timeout)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_http(&self, url: &str) -> Result<()> {
+        let (status, _) = http_get(url, self.timeout)?;
+
+        if (200..300).contains(&status) {
+            Ok(())
+        } else {
+            Err(Error::Unhealthy(format!("{}: HTTP status {}", url, status)))
+        }
+    }
+}
+
+/// Sends a GET request to the plain-HTTP URL and returns the status and the body of the response.
+pub fn http_get(url: &str, timeout: Duration) -> Result<(u16, String)> {
+    let (host, port, path) = parse_url(url)?;
+    let unhealthy = |e: &dyn ToString| Error::Unhealthy(format!("{}: {}", url, e.to_string()));
+
+    let addr = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|e| unhealthy(&e))?
+        .next()
+        .ok_or_else(|| unhealthy(&"Could not resolve the host"))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| unhealthy(&e))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| unhealthy(&e))?;
+    stream
+        .set_write_timeout(Some(timeout))
+        .map_err(|e| unhealthy(&e))?;
+
+    // written at once so that a server responding to the first packet doesn't cut us off mid-request
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| unhealthy(&e))?;
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|e| unhealthy(&e))?;
+
+    let status = response
+        .lines()
+        .next()
+        .and_then(|l| l.split(' ').nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| unhealthy(&"Malformed HTTP response"))?;
+
+    let body = match response.find("\r\n\r\n") {
+        Some(idx) => response[idx + 4..].to_owned(),
+        None => String::new(),
+    };
+
+    Ok((status, body))
+}
+
+/// Runs the command, killing it if it doesn't finish in time.
+fn check_command(cmd: &str, timeout: Duration) -> Result<()> {
+    let status = shell::output(Command::new("sh").arg("-c").arg(cmd), timeout)
+        .map_err(|e| Error::Unhealthy(format!("Failed to run `{}`: {}", cmd, e)))?
+        .status;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Unhealthy(format!(
+            "`{}` exited with {}",
+            cmd, status
+        )))
+    }
+}
+
+/// Splits a plain-HTTP URL into its host, port and path.
+pub fn parse_url(url: &str) -> Result<(String, u16, String)> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| {
+        Error::ConfigError(format!("Only plain http:// URLs are supported: {}", url))
+    })?;
+
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+
+    let (host, port) = match authority.rfind(':') {
+        Some(idx) if !authority.ends_with(']') => (
+            &authority[..idx],
+            authority[idx + 1..]
+                .parse::<u16>()
+                .map_err(|e| Error::ConfigError(format!("Invalid port in {}: {}", url, e)))?,
+        ),
+        _ => (authority, 80),
+    };
+
+    if host.is_empty() {
+        return Err(Error::ConfigError(format!("No host in {}", url)));
+    }
+
+    Ok((host.to_owned(), port, path.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_url_parsing() {
+        assert_eq!(
+            ("localhost".to_string(), 8080, "/healthz".to_string()),
+            parse_url("http://localhost:8080/healthz").unwrap()
+        );
+        assert_eq!(
+            ("example.com".to_string(), 80, "/".to_string()),
+            parse_url("http://example.com").unwrap()
+        );
+        assert!(parse_url("https://example.com").is_err());
+    }
+
+    fn serve_once(status_line: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            let _ = write!(stream, "{}\r\n\r\n", status_line);
+        });
+        format!("http://127.0.0.1:{}/healthz", port)
+    }
+
+    #[test]
+    fn test_http_check() {
+        let ok = HealthChecker::new(
+            vec![HealthCheck::Http(serve_once("HTTP/1.1 200 OK"))],
+            Duration::from_millis(0),
+        )
+        .unwrap();
+        assert!(ok.check(None).is_ok());
+
+        let failing = HealthChecker::new(
+            vec![HealthCheck::Http(serve_once(
+                "HTTP/1.1 503 Service Unavailable",
+            ))],
+            Duration::from_millis(0),
+        )
+        .unwrap();
+        assert!(failing.check(None).is_err());
+    }
+
+    #[test]
+    fn test_command_check() {
+        let checker = HealthChecker::new(
+            vec![
+                HealthCheck::Command("true".into()),
+                HealthCheck::Command("false".into()),
+            ],
+            Duration::from_millis(0),
+        )
+        .unwrap();
+        assert!(checker.check(None).is_err());
+
+        let started = std::time::Instant::now();
+        assert!(check_command("sleep 30", Duration::from_millis(200)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,118 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// The annotation on a config map with the comma-separated glob patterns of the keys to persist.
+pub const INCLUDE_ANNOTATION: &str = "cm-bump/include-keys";
+
+/// The annotation on a config map with the comma-separated glob patterns of the keys not to persist.
+pub const EXCLUDE_ANNOTATION: &str = "cm-bump/exclude-keys";
+
+#[derive(Debug, Clone, Error)]
+#[error("Invalid key pattern: {0}")]
+pub struct Error(String);
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Decides which keys of a config map are persisted. A key is persisted if it matches any of the include patterns,
+/// if there are any, and none of the exclude patterns.
+#[derive(Debug, Clone, Default)]
+pub struct KeyFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl KeyFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(KeyFilter {
+            include: glob_set(include)?,
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    /// Reads the filter from the annotations of a config map. Returns `None` if there are no filtering annotations.
+    pub fn from_annotations(annotations: &BTreeMap<String, String>) -> Result<Option<Self>> {
+        let include = patterns(annotations.get(INCLUDE_ANNOTATION));
+        let exclude = patterns(annotations.get(EXCLUDE_ANNOTATION));
+
+        if include.is_empty() && exclude.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(KeyFilter::new(&include, &exclude)?))
+        }
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        self.include
+            .as_ref()
+            .map(|i| i.is_match(key))
+            .unwrap_or(true)
+            && !self
+                .exclude
+                .as_ref()
+                .map(|e| e.is_match(key))
+                .unwrap_or(false)
+    }
+}
+
+/// Splits the comma-separated patterns.
+fn patterns(value: Option<&String>) -> Vec<String> {
+    value
+        .map(|v| {
+            v.split(',')
+                .map(|p| p.trim())
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for p in patterns {
+        builder.add(Glob::new(p).map_err(|e| Error(format!("{}: {}", p, e)))?);
+    }
+
+    builder
+        .build()
+        .map(Some)
+        .map_err(|e| Error(format!("{}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filter =
+            KeyFilter::new(&["*.conf".into(), "*.map".into()], &["test-*".into()]).unwrap();
+
+        assert!(filter.matches("nginx.conf"));
+        assert!(filter.matches("hosts.map"));
+        assert!(!filter.matches("README.md"));
+        assert!(!filter.matches("test-nginx.conf"));
+
+        assert!(KeyFilter::default().matches("README.md"));
+    }
+
+    #[test]
+    fn test_annotations() {
+        let mut annotations = BTreeMap::new();
+        assert!(KeyFilter::from_annotations(&annotations).unwrap().is_none());
+
+        annotations.insert(EXCLUDE_ANNOTATION.to_string(), "README*, *.md".to_string());
+        let filter = KeyFilter::from_annotations(&annotations).unwrap().unwrap();
+        assert!(filter.matches("nginx.conf"));
+        assert!(!filter.matches("README"));
+        assert!(!filter.matches("notes.md"));
+
+        annotations.insert(INCLUDE_ANNOTATION.to_string(), "[".to_string());
+        assert!(KeyFilter::from_annotations(&annotations).is_err());
+    }
+}
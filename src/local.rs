@@ -0,0 +1,221 @@
+use super::operator::{self, key};
+use super::source::{Event, EventSource};
+use futures::future::BoxFuture;
+use k8s_openapi::api::core::v1::ConfigMap;
+use serde::Deserialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The extensions of the files considered to be manifests. JSON is read as YAML.
+const EXTENSIONS: &[&str] = &["yaml", "yml", "json"];
+
+/// Reads config maps from the YAML or JSON manifests in a local directory instead of a Kubernetes cluster. Each file
+/// can contain several YAML documents, the documents of other kinds than `ConfigMap` are ignored. As an
+/// [EventSource](EventSource), the directory is re-read in the configured interval.
+#[derive(Debug)]
+pub struct LocalSource {
+    dir: PathBuf,
+    interval: Duration,
+    next_poll: Option<Instant>,
+    /// Whether the directory has been read at least once.
+    synced: bool,
+    pending: VecDeque<Event<ConfigMap>>,
+    /// The config maps read from each file. A file that fails to parse keeps its previous config maps, so that
+    /// half-written files don't look like deletions.
+    files: BTreeMap<PathBuf, Vec<ConfigMap>>,
+    /// The config maps as last reported, by their keys.
+    objects: BTreeMap<String, ConfigMap>,
+}
+
+impl LocalSource {
+    pub fn new(dir: &Path, interval: Duration) -> Self {
+        LocalSource {
+            dir: dir.to_owned(),
+            interval,
+            next_poll: None,
+            synced: false,
+            pending: VecDeque::new(),
+            files: BTreeMap::new(),
+            objects: BTreeMap::new(),
+        }
+    }
+
+    /// Re-reads the directory and returns the events describing the changes since the last read. The first read
+    /// results in a single resync with all the config maps.
+    pub fn poll(&mut self) -> Vec<Event<ConfigMap>> {
+        let paths = match manifest_paths(&self.dir) {
+            Ok(paths) => paths,
+            Err(e) => {
+                log::error!("Failed to list the manifests in {:?}: {}", self.dir, e);
+                return vec![];
+            }
+        };
+
+        let mut files = BTreeMap::new();
+        for path in paths {
+            match read_manifests(&path) {
+                Ok(cms) => {
+                    files.insert(path, cms);
+                }
+                Err(e) => {
+                    log::warn!("Failed to read the manifests in {:?}: {}", path, e);
+                    if let Some(previous) = self.files.remove(&path) {
+                        files.insert(path, previous);
+                    }
+                }
+            }
+        }
+        self.files = files;
+
+        let mut objects = BTreeMap::new();
+        for cm in self.files.values().flatten() {
+            objects.insert(key(cm), cm.clone());
+        }
+
+        if !self.synced {
+            self.synced = true;
+            self.objects = objects;
+            return vec![Event::Resync(
+                None,
+                self.objects.values().cloned().collect(),
+            )];
+        }
+
+        let mut events = vec![];
+        for (k, old) in &self.objects {
+            if !objects.contains_key(k) {
+                events.push(Event::Deleted(old.clone()));
+            }
+        }
+        for (k, new) in &objects {
+            match self.objects.get(k) {
+                None => events.push(Event::Added(new.clone())),
+                Some(old) if old != new => events.push(Event::Modified(new.clone())),
+                Some(_) => {}
+            }
+        }
+
+        self.objects = objects;
+
+        events
+    }
+}
+
+impl EventSource<ConfigMap> for LocalSource {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Event<ConfigMap>, operator::Error>>> {
+        Box::pin(async move {
+            loop {
+                if let Some(ev) = self.pending.pop_front() {
+                    return Some(Ok(ev));
+                }
+
+                // the deadline survives the future being dropped, so the polls keep their pace
+                if let Some(at) = self.next_poll {
+                    tokio::time::delay_until(at).await;
+                }
+                self.next_poll = Some(Instant::now() + self.interval);
+
+                let events = self.poll();
+                self.pending.extend(events);
+            }
+        })
+    }
+}
+
+fn manifest_paths(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_manifest = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| EXTENSIONS.contains(&e))
+            .unwrap_or(false);
+        if is_manifest && path.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Reads all the config maps in the file.
+fn read_manifests(path: &Path) -> Result<Vec<ConfigMap>, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    let mut cms = vec![];
+    for document in serde_yaml::Deserializer::from_str(&content) {
+        let value = serde_yaml::Value::deserialize(document).map_err(|e| e.to_string())?;
+        if value.get("kind").and_then(|k| k.as_str()) != Some("ConfigMap") {
+            log::debug!("Ignoring a document that is not a config map in {:?}", path);
+            continue;
+        }
+
+        let cm: ConfigMap = serde_yaml::from_value(value).map_err(|e| e.to_string())?;
+        match cm.metadata {
+            Some(ref m) if m.name.is_some() => cms.push(cm),
+            _ => return Err("config map without a name".into()),
+        }
+    }
+
+    Ok(cms)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(events: &[Event<ConfigMap>]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| match e {
+                Event::Added(cm) => format!("added {}", key(cm)),
+                Event::Modified(cm) => format!("modified {}", key(cm)),
+                Event::Deleted(cm) => format!("deleted {}", key(cm)),
+                Event::Resync(_, cms) => format!(
+                    "resync {}",
+                    cms.iter().map(key).collect::<Vec<_>>().join(", ")
+                ),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_changes_in_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("nginx.yaml");
+        let mut source = LocalSource::new(dir.path(), Duration::from_secs(1));
+
+        fs::write(
+            &manifest,
+            "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: nginx\n  namespace: app\ndata:\n  nginx.conf: v1\n\
+             ---\napiVersion: v1\nkind: Secret\nmetadata:\n  name: tls\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("haproxy.json"),
+            r#"{"kind": "ConfigMap", "metadata": {"name": "haproxy"}, "data": {"haproxy.cfg": "v1"}}"#,
+        )
+        .unwrap();
+        fs::write(dir.path().join("README.md"), "not a manifest").unwrap();
+
+        assert_eq!(vec!["resync app/nginx, haproxy"], names(&source.poll()));
+        assert!(source.poll().is_empty());
+
+        // a half-written file keeps the previous state
+        fs::write(&manifest, "apiVersion: v1\nkind: ConfigMap\nmetadata: [").unwrap();
+        assert!(source.poll().is_empty());
+
+        fs::write(
+            &manifest,
+            "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: nginx\n  namespace: app\ndata:\n  nginx.conf: v2\n",
+        )
+        .unwrap();
+        assert_eq!(vec!["modified app/nginx"], names(&source.poll()));
+
+        fs::remove_file(&manifest).unwrap();
+        assert_eq!(vec!["deleted app/nginx"], names(&source.poll()));
+    }
+}
@@ -5,19 +5,53 @@ use kube::{
     config::Config,
     Client,
 };
-use log;
 use pretty_env_logger::formatted_timed_builder;
 use regex::Regex;
 use std::env;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 
 mod bumper;
+mod container;
+mod downward;
+mod events;
+mod health;
+mod keys;
+mod local;
+mod metrics;
 mod operator;
+mod pidfd;
+mod procfs;
+mod ratelimit;
+mod reload;
+mod secrets;
+mod sequence;
+mod shell;
+mod socket;
+mod source;
+mod template;
 mod updater;
+mod validator;
 
 const LOG_ENV_VAR: &str = "CM_LOG";
 
+/// The flags that can also be enabled by setting their environment variable to `true`. clap reads the environment
+/// variables only for the options taking a value, so these flags are added to the arguments before they are parsed.
+const ENV_FLAGS: &[(&str, &str)] = &[
+    ("CM_HEALTH_PROCESS", "--health-check-process"),
+    ("CM_PROC_EXCLUDE_ANCESTORS", "--exclude-ancestors"),
+    ("CM_PROC_EXCLUDE_ZOMBIES", "--exclude-zombies"),
+    ("CM_PROC_WAIT_BEFORE_WRITE", "--wait-before-write"),
+    ("CM_RELOAD_START_TIME", "--reload-check-start-time"),
+    ("CM_RELOAD_WORKERS", "--reload-check-workers"),
+    ("CM_ALL_NAMESPACES", "--all-namespaces"),
+];
+
 #[derive(StructOpt, Debug)]
 #[structopt(rename_all = "kebab-case")]
 struct Opts {
@@ -25,9 +59,56 @@ struct Opts {
     #[structopt(short, long, env = "CM_DIR")]
     dir: String,
 
-    /// The namespace in which to look for the config maps to persist.
-    #[structopt(short, long, env = "CM_NAMESPACE")]
-    namespace: String,
+    /// Glob patterns, separated by commas, of the config map keys to persist. All keys are persisted by default.
+    /// A config map can further restrict its keys by the `cm-bump/include-keys` annotation.
+    #[structopt(long, env = "CM_INCLUDE_KEYS", use_delimiter = true)]
+    include_keys: Vec<String>,
+
+    /// Glob patterns, separated by commas, of the config map keys not to persist, e.g. `README*`. A config map can
+    /// exclude more keys by the `cm-bump/exclude-keys` annotation. None of the keys of a config map with invalid
+    /// patterns in the annotations are persisted.
+    #[structopt(long, env = "CM_EXCLUDE_KEYS", use_delimiter = true)]
+    exclude_keys: Vec<String>,
+
+    /// The directory with the Downward API files of the pod. The config maps with the `cm-bump/template: "true"`
+    /// annotation can refer to the files as `{{ pod.NAME }}`, next to the environment variables as `{{ env.NAME }}`,
+    /// the other keys of the config map as `{{ key.NAME }}` and the keys of the secrets in the same namespace as
+    /// `{{ secret.NAME.KEY }}`. The referenced secrets are watched, which requires the `get`, `list` and `watch`
+    /// permissions on them, preferably granted by a Role rule limited to the referenced secrets by `resourceNames`.
+    /// No access to the secrets is needed without such references. The referenced Downward API files are checked for
+    /// changes, e.g. of the pod labels, every few seconds.
+    #[structopt(long, env = "CM_DOWNWARD_API_DIR")]
+    downward_api_dir: Option<PathBuf>,
+
+    /// The namespaces in which to look for the config maps to persist, separated by commas.
+    #[structopt(
+        short,
+        long,
+        env = "CM_NAMESPACE",
+        use_delimiter = true,
+        required_unless_one = &["all-namespaces", "local-dir"]
+    )]
+    namespace: Vec<String>,
+
+    /// Look for the config maps to persist in all namespaces.
+    /// Can also be enabled by setting `CM_ALL_NAMESPACES` to `true`.
+    #[structopt(long, conflicts_with = "namespace")]
+    all_namespaces: bool,
+
+    /// Read the config maps from the YAML or JSON manifests in this directory instead of a Kubernetes cluster. The
+    /// directory is re-read periodically, so that changes to the manifests are picked up. Useful for development and
+    /// testing without a cluster. The namespaces and the label and field selectors apply to the cluster only, so
+    /// they can't be combined with this option. Use the config map name filters instead.
+    #[structopt(
+        long,
+        env = "CM_LOCAL_DIR",
+        conflicts_with_all = &["all-namespaces", "namespace", "labels", "field-selector"]
+    )]
+    local_dir: Option<PathBuf>,
+
+    /// The number of seconds between re-reads of the local directory.
+    #[structopt(long, env = "CM_LOCAL_POLL_INTERVAL", default_value = "1")]
+    local_poll_interval: u64,
 
     /// Whether to require valid certificate chain. True by default.
     #[structopt(short, long, env = "CM_TLS_VERIFY")]
@@ -36,7 +117,22 @@ struct Opts {
     /// An expression to match the labels against. Consult the Kubernetes documentation for the
     /// syntax required.
     #[structopt(short, long, env = "CM_LABELS")]
-    labels: String,
+    labels: Option<String>,
+
+    /// An expression to match the fields, e.g. `metadata.name=nginx`, against. Consult the Kubernetes documentation
+    /// for the syntax required.
+    #[structopt(long, env = "CM_FIELD_SELECTOR")]
+    field_selector: Option<String>,
+
+    /// The names of the config maps to persist, separated by commas. A name can be qualified by the namespace, e.g.
+    /// `platform-config/nginx`. The config maps are filtered locally, in addition to the label and field selectors.
+    #[structopt(long, env = "CM_CONFIGMAPS", use_delimiter = true)]
+    configmap: Vec<String>,
+
+    /// A regular expression the names of the config maps to persist need to match. The config maps are filtered
+    /// locally, in addition to the label and field selectors.
+    #[structopt(long, env = "CM_CONFIGMAP_REGEX")]
+    configmap_regex: Option<String>,
 
     /// The commandline by which to identify the process to send the signal to. This can be a regular expression.
     /// Ignored if process pid is specified.
@@ -47,6 +143,36 @@ struct Opts {
     #[structopt(short = "p", long, env = "CM_PROC_PID")]
     process_pid: Option<i32>,
 
+    /// The path to the pidfile of the process to send the signal to. The pidfile is re-read on each bump.
+    /// If process command is specified, too, the process needs to match it.
+    /// Ignored if process pid is specified.
+    #[structopt(short = "f", long, env = "CM_PROC_PID_FILE")]
+    process_pid_file: Option<PathBuf>,
+
+    /// The path to the executable of the process to send the signal to. If other process detection options are
+    /// specified, too, the process needs to match all of them. Ignored if process pid is specified.
+    #[structopt(long, env = "CM_PROC_EXE")]
+    process_exe: Option<PathBuf>,
+
+    /// The name of the process to send the signal to as found in `/proc/<pid>/comm`. If other process detection
+    /// options are specified, too, the process needs to match all of them. Ignored if process pid is specified.
+    /// The name can be at most 15 bytes long, as the kernel truncates longer names.
+    #[structopt(long, env = "CM_PROC_COMM", parse(try_from_str = bumper::parse_comm))]
+    process_comm: Option<String>,
+
+    /// The UID of the owner of the process to send the signal to. If other process detection options are
+    /// specified, too, the process needs to match all of them. Ignored if process pid is specified.
+    #[structopt(long, env = "CM_PROC_UID")]
+    process_uid: Option<u32>,
+
+    /// The name of the container, in the pod cm-bump is running in, of the process to send the signal to. This is
+    /// useful with `shareProcessNamespace` where identically named processes can run in several containers. The
+    /// container is recognized from `/proc/<pid>/cgroup` using the container ID from the pod status, so pod-name needs
+    /// to be specified, too. If other process detection options are specified, too, the process needs to match all of
+    /// them. Ignored if process pid is specified.
+    #[structopt(long, env = "CM_PROC_CONTAINER", requires = "pod-name")]
+    process_container: Option<String>,
+
     /// The commandline by which to identify the parent process of the process to send signal to. This can be a regular expression.
     /// Ignored if parent process pid is specified.
     #[structopt(short = "a", long, env = "CMD_PROC_PARENT_CMD")]
@@ -56,10 +182,204 @@ struct Opts {
     #[structopt(short = "i", long, env = "CMD_PROC_PARENT_PID")]
     process_parent_pid: Option<i32>,
 
+    /// The path to the pidfile of the parent process of the process to send the signal to. If parent process command
+    /// is specified, too, the parent process needs to match it. Ignored if parent process pid is specified.
+    #[structopt(long, env = "CMD_PROC_PARENT_PID_FILE")]
+    process_parent_pid_file: Option<PathBuf>,
+
+    /// The path to the executable of the parent process of the process to send the signal to. If other parent
+    /// process detection options are specified, too, the parent process needs to match all of them.
+    /// Ignored if parent process pid is specified.
+    #[structopt(long, env = "CMD_PROC_PARENT_EXE")]
+    process_parent_exe: Option<PathBuf>,
+
+    /// The name of the parent process of the process to send the signal to as found in `/proc/<pid>/comm`.
+    /// If other parent process detection options are specified, too, the parent process needs to match all of them.
+    /// Ignored if parent process pid is specified. The name can be at most 15 bytes long.
+    #[structopt(long, env = "CMD_PROC_PARENT_COMM", parse(try_from_str = bumper::parse_comm))]
+    process_parent_comm: Option<String>,
+
+    /// The UID of the owner of the parent process of the process to send the signal to. If other parent process
+    /// detection options are specified, too, the parent process needs to match all of them.
+    /// Ignored if parent process pid is specified.
+    #[structopt(long, env = "CMD_PROC_PARENT_UID")]
+    process_parent_uid: Option<u32>,
+
+    /// The name of the container of the parent process of the process to send the signal to. See process-container.
+    /// If other parent process detection options are specified, too, the parent process needs to match all of them.
+    /// Ignored if parent process pid is specified.
+    #[structopt(long, env = "CMD_PROC_PARENT_CONTAINER", requires = "pod-name")]
+    process_parent_container: Option<String>,
+
+    /// How many levels above the process the parent process can be found, e.g. 2 if the process is started by
+    /// `sh -c` launched by the parent process. 0 means any depth. The default is 1, i.e. the direct parent.
+    #[structopt(long, env = "CMD_PROC_PARENT_DEPTH", default_value = "1")]
+    process_parent_depth: u32,
+
+    /// Never match the ancestors of cm-bump, e.g. the shell that launched it, when scanning for the processes.
+    /// cm-bump itself is never matched.
+    /// Can also be enabled by setting `CM_PROC_EXCLUDE_ANCESTORS` to `true`.
+    #[structopt(long)]
+    exclude_ancestors: bool,
+
+    /// Never match zombie processes when scanning for the processes.
+    /// Can also be enabled by setting `CM_PROC_EXCLUDE_ZOMBIES` to `true`.
+    #[structopt(long)]
+    exclude_zombies: bool,
+
+    /// The number of seconds to wait for the process to appear if it hasn't been seen yet when the config files
+    /// change. Without this, changes made before the process starts don't bump it.
+    #[structopt(long, env = "CM_PROC_WAIT")]
+    wait_for_process: Option<u64>,
+
+    /// Don't write the config files until the process has been seen or the wait for it timed out. This makes the
+    /// startup ordering deterministic. Requires wait-for-process.
+    /// Can also be enabled by setting `CM_PROC_WAIT_BEFORE_WRITE` to `true`.
+    #[structopt(long, requires = "wait-for-process")]
+    wait_before_write: bool,
+
+    /// The procfs to look for the processes in, e.g. `/host/proc` if the procfs of the host is mounted there.
+    /// Note that the signals are still sent using the PIDs as found in this procfs, so cm-bump needs to share
+    /// the PID namespace with the processes.
+    #[structopt(long, env = "CM_PROC_ROOT", default_value = "/proc")]
+    proc_root: PathBuf,
+
     /// The name of the signal to send to the process on the configuration files change.
     /// Use `kill -l` to get a list of possible signals and prepend it with "SIG". E.g. "SIGHUP", "SIGKILL", etc.
+    /// This can also be a sequence of steps separated by `;`. Each step is
+    /// `SIGNAL[@process|initial|children][,delay=DURATION][,wait=DURATION]`. `initial` sends the signal to the process
+    /// found at the start of the sequence, `children` to its children. `delay` waits before sending the signal.
+    /// `wait` waits for the targets to exit and skips the remaining steps if they do. The durations are in seconds or
+    /// in milliseconds with the `ms` suffix. E.g. `SIGTERM,wait=10;SIGKILL` or
+    /// `SIGUSR2@initial;SIGWINCH@initial,delay=1;SIGQUIT@initial`. The delays and waits don't hold up the processing
+    /// of other changes, they are checked once per second.
     #[structopt(short, long, env = "CM_PROC_SIGNAL")]
     signal: Option<String>,
+
+    /// The minimum number of seconds between 2 bumps of the process. The changes arriving sooner are written right away
+    /// but the bump, including the socket commands, is postponed and merged with any other changes arriving in the
+    /// meantime.
+    #[structopt(long, env = "CM_BUMP_MIN_INTERVAL", default_value = "0")]
+    bump_min_interval: u64,
+
+    /// The number of bumps that can happen in a quick succession before they are limited to 1 per
+    /// `--bump-refill-interval`. The suppressed bumps are merged into the next allowed one. Needs to be at least 1.
+    #[structopt(long, env = "CM_BUMP_BURST", parse(try_from_str = ratelimit::parse_capacity))]
+    bump_burst: Option<u32>,
+
+    /// The number of seconds after which 1 more bump is allowed when the `--bump-burst` is exhausted.
+    #[structopt(long, env = "CM_BUMP_REFILL_INTERVAL", default_value = "60")]
+    bump_refill_interval: u64,
+
+    /// The path to a Unix domain socket of a runtime API, e.g. the HAProxy stats socket, to send the socket commands to
+    /// on the configuration files change.
+    #[structopt(long, env = "CM_SOCKET_PATH")]
+    socket_path: Option<String>,
+
+    /// A command template to send to the socket for each changed file. Repeat the option for more commands, the
+    /// environment variable takes one command per line. `{file}` is replaced by the path to the changed file, `{name}`
+    /// by its name. Commands containing `{line}` are sent once for each non-empty, non-comment line of the file. A
+    /// command prefixed by a glob and `=>` is only sent for the files matching the glob. E.g.
+    /// `--socket-command '*.map => clear map {file}' --socket-command '*.map => add map {file} {line}'`.
+    /// A command getting a non-empty reply fails the bump, unless the reply matches `--socket-ok-reply`.
+    #[structopt(
+        long,
+        env = "CM_SOCKET_CMD",
+        value_delimiter = "\n",
+        requires = "socket-path"
+    )]
+    socket_command: Vec<String>,
+
+    /// A command template to send to the socket for each deleted file, in the same form as `--socket-command`, e.g.
+    /// `*.map => clear map {file}`.
+    #[structopt(
+        long,
+        env = "CM_SOCKET_DELETE_CMD",
+        value_delimiter = "\n",
+        requires = "socket-path"
+    )]
+    socket_delete_command: Vec<String>,
+
+    /// A regular expression matching the non-empty replies to the socket commands that are not errors, e.g.
+    /// `^IP changed` for `set server` of HAProxy.
+    #[structopt(long, env = "CM_SOCKET_OK_REPLY", requires = "socket-path")]
+    socket_ok_reply: Option<String>,
+
+    /// A command to validate the config files with before they are written. The command is run by `sh -c` against
+    /// a staged copy of the directory with the changes applied. The `{dir}` placeholder in the command and the
+    /// `CM_STAGING_DIR` environment variable both contain the path to the staged directory. If the command fails,
+    /// the changes are neither written nor is the process signalled. E.g. `nginx -t -c {dir}/nginx.conf`.
+    #[structopt(long, env = "CM_VALIDATE_CMD")]
+    validate_command: Option<String>,
+
+    /// The number of seconds the validation command may run. A command still running after that is killed and the
+    /// changes are not applied.
+    #[structopt(long, env = "CM_VALIDATE_TIMEOUT", default_value = "30")]
+    validate_timeout: u64,
+
+    /// Check that the bumped process is still running after the bump. If it isn't, the last known good revision
+    /// of the changed config map is restored and the process bumped again.
+    /// Can also be enabled by setting `CM_HEALTH_PROCESS` to `true`.
+    #[structopt(long)]
+    health_check_process: bool,
+
+    /// A plain-HTTP URL that needs to return a 2xx status after the bump. If it doesn't, the last known good revision
+    /// of the changed config map is restored and the process bumped again.
+    #[structopt(long, env = "CM_HEALTH_URL")]
+    health_check_url: Option<String>,
+
+    /// A command, run by `sh -c`, that needs to succeed after the bump. If it doesn't, the last known good revision
+    /// of the changed config map is restored and the process bumped again. A command still running after 5 seconds is
+    /// killed and counts as failed.
+    #[structopt(long, env = "CM_HEALTH_CMD")]
+    health_check_command: Option<String>,
+
+    /// The number of seconds to wait after the bump before checking the health of the process.
+    #[structopt(long, env = "CM_HEALTH_DELAY", default_value = "5")]
+    health_check_delay: u64,
+
+    /// After each bump, check that the process has been restarted, i.e. its start time changed.
+    /// Can also be enabled by setting `CM_RELOAD_START_TIME` to `true`.
+    #[structopt(long)]
+    reload_check_start_time: bool,
+
+    /// After each bump, check that the children of the process, e.g. nginx workers, have been replaced.
+    /// Can also be enabled by setting `CM_RELOAD_WORKERS` to `true`.
+    #[structopt(long)]
+    reload_check_workers: bool,
+
+    /// After each bump, check that a line matching `--reload-check-log-line` has been appended to this file.
+    #[structopt(long, env = "CM_RELOAD_LOG_FILE", requires = "reload-check-log-line")]
+    reload_check_log_file: Option<PathBuf>,
+
+    /// The regular expression the line appended to `--reload-check-log-file` needs to match.
+    #[structopt(long, env = "CM_RELOAD_LOG_LINE", requires = "reload-check-log-file")]
+    reload_check_log_line: Option<String>,
+
+    /// After each bump, check that the body returned from this plain-HTTP URL, e.g. a hash of the loaded config,
+    /// changed. The requests time out with the reload checks.
+    #[structopt(long, env = "CM_RELOAD_URL")]
+    reload_check_url: Option<String>,
+
+    /// The number of seconds to wait for the reload checks to pass. The outcome is logged, reported as an Event and
+    /// counted in the metrics, a failed reload check doesn't cause a rollback.
+    #[structopt(long, env = "CM_RELOAD_TIMEOUT", default_value = "10")]
+    reload_check_timeout: u64,
+
+    /// The address, e.g. `0.0.0.0:9090`, to serve the metrics on in the Prometheus text format. The metrics count
+    /// the outcomes of the reload checks.
+    #[structopt(long, env = "CM_METRICS_ADDRESS")]
+    metrics_address: Option<SocketAddr>,
+
+    /// The name of the pod cm-bump is running in. If specified, notable occurrences like rollbacks are reported as
+    /// Kubernetes Events on the pod. Use the Downward API to obtain it.
+    #[structopt(long, env = "CM_POD_NAME")]
+    pod_name: Option<String>,
+
+    /// The namespace of the pod cm-bump is running in. Defaults to the first of the watched namespaces. Use the
+    /// Downward API to obtain it.
+    #[structopt(long, env = "CM_POD_NAMESPACE")]
+    pod_namespace: Option<String>,
 }
 
 #[tokio::main]
@@ -73,21 +393,96 @@ async fn main() -> anyhow::Result<()> {
         .parse_filters(&env::var(LOG_ENV_VAR).unwrap_or("info,kube=warn".into()))
         .init();
 
-    let opt: Opts = StructOpt::from_args();
+    let opt = Opts::from_iter(args()?);
 
     log::info!("cm-bump starting");
 
-    let mut client_config = Config::infer().await?;
-    client_config.accept_invalid_certs = !opt.tls_verify.unwrap_or(true);
+    let client = match opt.local_dir {
+        Some(ref dir) => {
+            log::info!(
+                "Reading config maps from the manifests in {:?} instead of a cluster.",
+                dir
+            );
+            None
+        }
+        None => {
+            let mut client_config = Config::infer().await?;
+            client_config.accept_invalid_certs = !opt.tls_verify.unwrap_or(true);
+            Some(Client::try_from(client_config)?)
+        }
+    };
+
+    let pod_namespace = opt
+        .pod_namespace
+        .clone()
+        .or_else(|| opt.namespace.first().cloned());
+    let mut lp = ListParams::default();
+    if let Some(ref labels) = opt.labels {
+        lp = lp.labels(labels);
+    }
+    if let Some(ref fields) = opt.field_selector {
+        lp = lp.fields(fields);
+    }
 
-    let client = Client::try_from(client_config)?;
-    let cms: Api<ConfigMap> = Api::namespaced(client, &opt.namespace);
-    let lp = ListParams::default().labels(&opt.labels);
+    let mut filter = operator::NameFilter::default();
+    if !opt.configmap.is_empty() {
+        log::info!("Only config maps {:?} will be persisted.", opt.configmap);
+        filter = filter.with_names(opt.configmap.clone());
+    }
+    if let Some(ref regex) = opt.configmap_regex {
+        log::info!(
+            "Only config maps with names matching `{}` will be persisted.",
+            regex
+        );
+        filter = filter.with_regex(Regex::new(regex)?);
+    }
+    if opt.configmap.is_empty()
+        && opt.configmap_regex.is_none()
+        && opt.labels.is_none()
+        && opt.field_selector.is_none()
+    {
+        log::warn!(
+            "No config map selector configured, the keys of ALL config maps in the watched namespaces, including \
+             kube-root-ca.crt, will be persisted. Use --configmap, --configmap-regex, --labels or --field-selector to \
+             select the config maps."
+        );
+    }
 
-    let bumper = match bumper_config(&opt) {
+    let mut bumper = match bumper_config(&opt) {
         Some((detection, signal)) => {
             log::info!("Bumper will look for processes matching hierarchy `{:?}` and send `{}` to it on config change.", detection, signal);
-            Some(bumper::Bumper::new(detection, &signal)?)
+            let containers = containers(&detection);
+            if let (false, Some(pod_name), Some(pod_namespace), Some(client)) = (
+                containers.is_empty(),
+                &opt.pod_name,
+                &pod_namespace,
+                &client,
+            ) {
+                log::info!(
+                    "Tracking the IDs of containers {:?} in pod `{}`.",
+                    containers.iter().map(|c| c.name()).collect::<Vec<_>>(),
+                    pod_name
+                );
+                tokio::spawn(container::track(
+                    client.clone(),
+                    pod_namespace.clone(),
+                    pod_name.clone(),
+                    containers,
+                ));
+            }
+            Some(
+                bumper::Bumper::new(detection, &signal)?
+                    .with_scan_options(bumper::ScanOptions {
+                        exclude_ancestors: opt.exclude_ancestors,
+                        exclude_zombies: opt.exclude_zombies,
+                    })
+                    .with_relation(match opt.process_parent_depth {
+                        0 => bumper::Relation::Ancestor(None),
+                        1 => bumper::Relation::Parent,
+                        depth => bumper::Relation::Ancestor(Some(depth)),
+                    })
+                    .with_proc(procfs::ProcFs::new(&opt.proc_root)),
+            )
         }
         None => {
             log::info!("Bumper not configured.");
@@ -95,36 +490,241 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
-    let op = match updater::ConfigUpdater::new(&opt.dir, bumper) {
-        Ok(cu) => cu,
+    if let Some(secs) = opt.wait_for_process {
+        if let Some(b) = bumper.take() {
+            let mut b = b.with_wait_timeout(Duration::from_secs(secs));
+            if opt.wait_before_write {
+                log::info!(
+                    "Waiting up to {}s for the process to appear before writing the config files.",
+                    secs
+                );
+                if !b.wait_for_target() {
+                    log::warn!(
+                        "The process didn't appear in {}s. Writing the config files anyway.",
+                        secs
+                    );
+                }
+            }
+            bumper = Some(b);
+        }
+    }
+
+    let limiter = if opt.bump_min_interval > 0 || opt.bump_burst.is_some() {
+        let mut limiter = ratelimit::RateLimiter::new(Duration::from_secs(opt.bump_min_interval));
+        if let Some(burst) = opt.bump_burst {
+            limiter =
+                limiter.with_token_bucket(burst, Duration::from_secs(opt.bump_refill_interval));
+        }
+        log::info!("The bumps will be limited by {:?}.", limiter);
+        Some(limiter)
+    } else {
+        None
+    };
+
+    if let Some(ref limiter) = limiter {
+        bumper = bumper.map(|b| b.with_rate_limiter(limiter.clone()));
+    }
+
+    let mut op = match updater::ConfigUpdater::new(&opt.dir, bumper) {
+        Ok(cu) => match opt.validate_command {
+            Some(ref cmd) => {
+                log::info!("Config changes will be validated using `{}`.", cmd);
+                cu.with_validator(
+                    validator::Validator::new(cmd)
+                        .with_timeout(Duration::from_secs(opt.validate_timeout)),
+                )
+            }
+            None => cu,
+        },
         Err(e) => {
             log::error!("{}", e);
             anyhow::bail!("{}", e)
         }
     };
 
-    operator::run(cms, op, lp).await?;
+    if !opt.include_keys.is_empty() || !opt.exclude_keys.is_empty() {
+        log::info!(
+            "Only the keys matching {:?} and not matching {:?} will be persisted.",
+            opt.include_keys,
+            opt.exclude_keys
+        );
+        op = op.with_key_filter(keys::KeyFilter::new(&opt.include_keys, &opt.exclude_keys)?);
+    }
+
+    let secrets = secrets::SecretStore::default();
+    let mut renderer = template::Renderer::default();
+    if client.is_some() {
+        renderer = renderer.with_secrets(secrets.clone());
+    }
+    if let Some(ref dir) = opt.downward_api_dir {
+        log::info!(
+            "The config map templates can refer to the Downward API files in {:?}.",
+            dir
+        );
+        renderer = renderer.with_downward_api_dir(dir);
+    }
+    op = op.with_renderer(renderer);
+
+    if let Some(ref socket_path) = opt.socket_path {
+        log::info!(
+            "Bumper will send `{:?}` for the changed and `{:?}` for the deleted files to socket `{}` on config change.",
+            opt.socket_command,
+            opt.socket_delete_command,
+            socket_path
+        );
+        let mut socket_bumper = socket::SocketBumper::new(
+            std::path::Path::new(socket_path),
+            &opt.socket_command,
+            &opt.socket_delete_command,
+        )?;
+        if let Some(ref ok_reply) = opt.socket_ok_reply {
+            socket_bumper = socket_bumper.with_ok_reply(Regex::new(ok_reply)?);
+        }
+        if let Some(ref limiter) = limiter {
+            socket_bumper = socket_bumper.with_rate_limiter(limiter.clone());
+        }
+        op = op.with_socket_bumper(socket_bumper);
+    }
+
+    if let Some(checker) = health_check_config(&opt)? {
+        log::info!(
+            "The health of the process will be checked after each bump using {:?}.",
+            checker
+        );
+        op = op.with_health_checker(checker);
+    }
+
+    if let Some(verifier) = reload_check_config(&opt)? {
+        log::info!(
+            "The reload of the config will be verified after each bump using {:?}.",
+            verifier
+        );
+        op = op.with_reload_verifier(verifier);
+    }
+
+    if let Some(addr) = opt.metrics_address {
+        let m = Arc::new(metrics::Metrics::default());
+        let addr = metrics::serve(addr, m.clone())?;
+        log::info!("Serving the metrics on {}.", addr);
+        op = op.with_metrics(m);
+    }
+
+    if let (Some(pod_name), Some(client)) = (&opt.pod_name, &client) {
+        let pod_namespace = match pod_namespace {
+            Some(ns) => ns,
+            None => anyhow::bail!(
+                "The pod namespace needs to be specified when watching all namespaces."
+            ),
+        };
+        log::info!(
+            "Events will be reported on pod `{}` in namespace `{}`.",
+            pod_name,
+            pod_namespace
+        );
+        let (recorder, notifications) = events::EventRecorder::new();
+        tokio::spawn(events::publish(
+            client.clone(),
+            pod_namespace,
+            pod_name.clone(),
+            notifications,
+        ));
+        op = op.with_event_recorder(recorder);
+    }
+
+    match (opt.local_dir, client) {
+        (Some(dir), _) => {
+            let source =
+                local::LocalSource::new(&dir, Duration::from_secs(opt.local_poll_interval));
+            let source = downward::DownwardApiSource::new(
+                source,
+                opt.downward_api_dir,
+                downward::POLL_INTERVAL,
+            );
+            operator::drive(source, op, filter).await?
+        }
+        (None, Some(client)) => {
+            let cms: Vec<(Option<String>, Api<ConfigMap>)> = if opt.all_namespaces {
+                log::info!("Watching config maps in all namespaces.");
+                vec![(None, Api::all(client.clone()))]
+            } else {
+                log::info!("Watching config maps in namespaces {:?}.", opt.namespace);
+                opt.namespace
+                    .iter()
+                    .map(|ns| (Some(ns.clone()), Api::namespaced(client.clone(), ns)))
+                    .collect()
+            };
+            let source =
+                secrets::SecretsSource::new(source::KubeSource::new(cms, lp), client, secrets)
+                    .with_filter(filter.clone());
+            let source = downward::DownwardApiSource::new(
+                source,
+                opt.downward_api_dir,
+                downward::POLL_INTERVAL,
+            );
+            operator::drive(source, op, filter).await?
+        }
+        (None, None) => unreachable!("The client is only missing with a local directory."),
+    }
 
     Ok(())
 }
 
+/// The command line arguments, with the flags enabled by their environment variables added.
+fn args() -> anyhow::Result<Vec<OsString>> {
+    let mut args: Vec<OsString> = env::args_os().collect();
+    for (var, flag) in ENV_FLAGS {
+        let enabled = match env::var(var) {
+            Ok(value) => value.trim().parse::<bool>().map_err(|_| {
+                anyhow::anyhow!(
+                    "Invalid value `{}` of {}, expected `true` or `false`.",
+                    value,
+                    var
+                )
+            })?,
+            Err(_) => false,
+        };
+        if enabled && !args.iter().any(|a| a == flag) {
+            args.push(OsString::from(flag));
+        }
+    }
+    Ok(args)
+}
+
 fn bumper_config(opts: &Opts) -> Option<(Vec<bumper::ProcessDetection>, String)> {
     match opts.signal {
         Some(ref signal) => {
             let mut ret = vec![];
             let parent_process = process_detection_config(
-                &opts.process_parent_command,
-                &opts.process_parent_pid,
+                &ProcessOpts {
+                    cmd: &opts.process_parent_command,
+                    pid: &opts.process_parent_pid,
+                    pid_file: &opts.process_parent_pid_file,
+                    exe: &opts.process_parent_exe,
+                    comm: &opts.process_parent_comm,
+                    uid: &opts.process_parent_uid,
+                    container: &opts.process_parent_container,
+                },
                 "the parent",
             );
-            let process = process_detection_config(&opts.process_command, &opts.process_pid, "the");
+            let process = process_detection_config(
+                &ProcessOpts {
+                    cmd: &opts.process_command,
+                    pid: &opts.process_pid,
+                    pid_file: &opts.process_pid_file,
+                    exe: &opts.process_exe,
+                    comm: &opts.process_comm,
+                    uid: &opts.process_uid,
+                    container: &opts.process_container,
+                },
+                "the",
+            );
 
-            if parent_process.is_some() {
-                ret.push(parent_process.unwrap());
+            if let Some(parent_process) = parent_process {
+                ret.push(parent_process);
             }
 
-            if process.is_some() {
-                ret.push(process.unwrap());
+            if let Some(process) = process {
+                ret.push(process);
             }
 
             Some((ret, signal.clone()))
@@ -133,28 +733,154 @@ fn bumper_config(opts: &Opts) -> Option<(Vec<bumper::ProcessDetection>, String)>
     }
 }
 
+fn health_check_config(opts: &Opts) -> anyhow::Result<Option<health::HealthChecker>> {
+    let mut checks = vec![];
+
+    if opts.health_check_process {
+        checks.push(health::HealthCheck::ProcessAlive);
+    }
+
+    if let Some(ref url) = opts.health_check_url {
+        checks.push(health::HealthCheck::Http(url.clone()));
+    }
+
+    if let Some(ref cmd) = opts.health_check_command {
+        checks.push(health::HealthCheck::Command(cmd.clone()));
+    }
+
+    if checks.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(health::HealthChecker::new(
+            checks,
+            Duration::from_secs(opts.health_check_delay),
+        )?))
+    }
+}
+
+fn reload_check_config(opts: &Opts) -> anyhow::Result<Option<reload::ReloadVerifier>> {
+    let mut checks = vec![];
+
+    if opts.reload_check_start_time {
+        checks.push(reload::ReloadCheck::StartTimeChanged);
+    }
+
+    if opts.reload_check_workers {
+        checks.push(reload::ReloadCheck::WorkersReplaced);
+    }
+
+    if let (Some(ref file), Some(ref line)) =
+        (&opts.reload_check_log_file, &opts.reload_check_log_line)
+    {
+        checks.push(reload::ReloadCheck::LogLine(
+            file.clone(),
+            Regex::new(line)?,
+        ));
+    }
+
+    if let Some(ref url) = opts.reload_check_url {
+        checks.push(reload::ReloadCheck::ConfigHash(url.clone()));
+    }
+
+    if checks.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(reload::ReloadVerifier::new(
+            checks,
+            Duration::from_secs(opts.reload_check_timeout),
+        )?))
+    }
+}
+
+/// Collects the containers the process detections refer to.
+fn containers(detections: &[bumper::ProcessDetection]) -> Vec<container::ContainerRef> {
+    detections
+        .iter()
+        .flat_map(|d| match d {
+            bumper::ProcessDetection::Container(c) => vec![c.clone()],
+            bumper::ProcessDetection::All(all) => containers(all),
+            _ => vec![],
+        })
+        .collect()
+}
+
+/// The options identifying a single process in the hierarchy.
+struct ProcessOpts<'a> {
+    cmd: &'a Option<String>,
+    pid: &'a Option<i32>,
+    pid_file: &'a Option<PathBuf>,
+    exe: &'a Option<PathBuf>,
+    comm: &'a Option<String>,
+    uid: &'a Option<u32>,
+    container: &'a Option<String>,
+}
+
 fn process_detection_config(
-    cmd: &Option<String>,
-    pid: &Option<i32>,
+    opts: &ProcessOpts,
     adjective: &str,
 ) -> Option<bumper::ProcessDetection> {
-    match pid {
-        Some(pid) => {
-            if cmd.is_some() {
-                log::warn!("Ignoring {} process command configuration `{}` because {} PID `{}` has been specified.", 
-                    adjective, adjective, cmd.clone().unwrap(), pid);
+    let regex = opts.cmd.as_ref().map(|cmd| match Regex::from_str(cmd) {
+        Ok(regex) => regex,
+        Err(e) => {
+            log::error!("Failed to parse {} as a regular expression. Exitting.", e);
+            std::process::exit(1);
+        }
+    });
+
+    if let Some(pid) = opts.pid {
+        if let Some(ref cmd) = opts.cmd {
+            log::warn!("Ignoring {} process command configuration `{}` because {} PID `{}` has been specified.",
+                adjective, cmd, adjective, pid);
+        }
+        if let Some(ref pid_file) = opts.pid_file {
+            log::warn!("Ignoring {} process pidfile configuration `{:?}` because {} PID `{}` has been specified.",
+                adjective, pid_file, adjective, pid);
+        }
+        if opts.exe.is_some()
+            || opts.comm.is_some()
+            || opts.uid.is_some()
+            || opts.container.is_some()
+        {
+            log::warn!("Ignoring {} process exe, comm, uid and container configuration because {} PID `{}` has been specified.",
+                adjective, adjective, pid);
+        }
+        return Some(bumper::ProcessDetection::Pid(*pid));
+    }
+
+    let mut detections = vec![];
+
+    match opts.pid_file {
+        Some(ref pid_file) => {
+            detections.push(bumper::ProcessDetection::PidFile(pid_file.clone(), regex))
+        }
+        None => {
+            if let Some(regex) = regex {
+                detections.push(bumper::ProcessDetection::Cmdline(regex));
             }
-            Some(bumper::ProcessDetection::Pid(*pid))
-        }
-        None => match cmd {
-            Some(cmd) => match Regex::from_str(&cmd) {
-                Ok(regex) => Some(bumper::ProcessDetection::Cmdline(regex)),
-                Err(e) => {
-                    log::error!("Failed to parse {} as a regular expression. Exitting.", e);
-                    std::process::exit(1);
-                }
-            },
-            None => None,
-        },
+        }
+    }
+
+    if let Some(ref exe) = opts.exe {
+        detections.push(bumper::ProcessDetection::Exe(exe.clone()));
+    }
+
+    if let Some(ref comm) = opts.comm {
+        detections.push(bumper::ProcessDetection::Comm(comm.clone()));
+    }
+
+    if let Some(uid) = opts.uid {
+        detections.push(bumper::ProcessDetection::Uid(*uid));
+    }
+
+    if let Some(ref container) = opts.container {
+        detections.push(bumper::ProcessDetection::Container(
+            container::ContainerRef::new(container),
+        ));
+    }
+
+    match detections.len() {
+        0 => None,
+        1 => detections.pop(),
+        _ => Some(bumper::ProcessDetection::All(detections)),
     }
 }
@@ -0,0 +1,96 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long to wait for a scrape request to be read and its response written.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The counters of the outcomes of the bumps. Counting is lock-free and never blocks, the counters are exposed in
+/// the Prometheus text format by [serve](serve).
+#[derive(Debug, Default)]
+pub struct Metrics {
+    reloads_detected: AtomicU64,
+    reloads_not_detected: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a bump after which the process was verified to have reloaded the config.
+    pub fn reload_detected(&self) {
+        self.reloads_detected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a bump after which the reload of the config could not be verified.
+    pub fn reload_not_detected(&self) {
+        self.reloads_not_detected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders the counters in the Prometheus text format.
+    pub fn render(&self) -> String {
+        format!(
+            "# HELP cm_bump_reloads_total The outcomes of the verifications of the reloads after the bumps.\n\
+             # TYPE cm_bump_reloads_total counter\n\
+             cm_bump_reloads_total{{result=\"detected\"}} {}\n\
+             cm_bump_reloads_total{{result=\"not_detected\"}} {}\n",
+            self.reloads_detected.load(Ordering::Relaxed),
+            self.reloads_not_detected.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Serves the metrics over plain HTTP on the provided address from a background thread. Every request is answered
+/// with the metrics, regardless of its path. Fails if the address can't be bound.
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|s| respond(s, &metrics));
+            if let Err(e) = result {
+                log::debug!("Failed to serve the metrics: {}", e);
+            }
+        }
+    });
+
+    Ok(local_addr)
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+
+    // only the request line and headers matter, and only to be read before responding
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request)?;
+
+    let body = metrics.render();
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serve_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.reload_detected();
+        metrics.reload_detected();
+        metrics.reload_not_detected();
+
+        let addr = serve("127.0.0.1:0".parse().unwrap(), metrics.clone()).unwrap();
+        let url = format!("http://{}/metrics", addr);
+        let (status, body) = crate::health::http_get(&url, Duration::from_secs(5)).unwrap();
+
+        assert_eq!(200, status);
+        assert!(body.contains("cm_bump_reloads_total{result=\"detected\"} 2\n"));
+        assert!(body.contains("cm_bump_reloads_total{result=\"not_detected\"} 1\n"));
+    }
+}
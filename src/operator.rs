@@ -1,10 +1,9 @@
-use kube::{
-    runtime::Informer, api::{Api, Meta, ListParams, WatchEvent},
-};
-use log;
+use super::source::{Event, EventSource};
+use kube::api::Meta;
+use regex::Regex;
 use serde::de::DeserializeOwned;
+use std::collections::HashSet;
 use thiserror::Error;
-use futures::{StreamExt, TryStreamExt};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -14,68 +13,169 @@ pub enum Error {
     OperatorError(String),
 }
 
-/// The operator trait. Clients of this library implement this trait and pass it to the [run](run) method.
-pub trait Operator<Incoming, Stored>
-{
+/// The operator trait. Clients of this library implement this trait and pass it to the [drive](drive) method.
+pub trait Operator<Incoming, Stored> {
     fn prepare(&self, obj: Incoming) -> Stored;
 
     /// The operator reconsiles the state of the objects by implementing this method.
     /// If old is None, then the new object represents a newly created object, if new is None then the old represents an object
-    /// that has been deleted.
+    /// that has been deleted. If the reconciliation fails, the old object is kept as the state to reconcile the next
+    /// change with.
     fn reconcile(&mut self, old: Option<&Stored>, new: Option<&Stored>) -> Result<(), Error>;
+
+    /// Called periodically, regardless of any changes to the objects, to let the operator finish any postponed work.
+    fn tick(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Filters the objects by their names locally, before they are passed to the operator. An object needs to pass all
+/// the configured criteria.
+#[derive(Debug, Clone, Default)]
+pub struct NameFilter {
+    names: Option<HashSet<String>>,
+    regex: Option<Regex>,
+}
+
+impl NameFilter {
+    /// Only lets through the objects with the listed names. A name can be qualified by the namespace, e.g.
+    /// `platform-config/nginx`, to only match in that namespace.
+    pub fn with_names(mut self, names: Vec<String>) -> Self {
+        self.names = Some(names.into_iter().collect());
+        self
+    }
+
+    /// Only lets through the objects with the names matching the regex.
+    pub fn with_regex(mut self, regex: Regex) -> Self {
+        self.regex = Some(regex);
+        self
+    }
+
+    pub fn matches<Obj: Meta>(&self, object: &Obj) -> bool {
+        let name = object.name();
+        self.names
+            .as_ref()
+            .map(|names| names.contains(&name) || names.contains(&key(object)))
+            .unwrap_or(true)
+            && self
+                .regex
+                .as_ref()
+                .map(|r| r.is_match(&name))
+                .unwrap_or(true)
+    }
 }
 
-/// Runs the operator seeded with the list of the CR objects.
-/// This method is blocking indefinitely unless interrupted by an error.
-pub async fn run<Obj, Op, St>(api: Api<Obj>, operator: Op, params: ListParams) -> Result<(), Error>
+/// How often the [tick](Operator::tick) method of the operator is called.
+const TICK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Runs the operator on the events from the source until the source is exhausted or fails. Only the objects passing
+/// the filter are handed to the operator.
+pub async fn drive<Obj, Op, St, Src>(
+    mut source: Src,
+    operator: Op,
+    filter: NameFilter,
+) -> Result<(), Error>
 where
-    Obj: Clone + DeserializeOwned + Meta + PartialEq + std::fmt::Debug + Send + Sync,
+    Obj: Clone + DeserializeOwned + Meta + PartialEq + std::fmt::Debug,
     Op: Operator<Obj, St>,
+    Src: EventSource<Obj>,
 {
-    let inf = Informer::new(api).params(params);
-
     let mut operator_state = OperatorState::new(operator);
+    let mut ticks = tokio::time::interval(TICK_INTERVAL);
 
     loop {
-        let mut stream = inf.poll().await?.boxed();
-        while let Some(ev) = stream.try_next().await? {
-            match ev {
-                WatchEvent::Added(o) => {
-                    match operator_state.on_create(o) {
-                        Ok(_) => {}
-                        Err(e) => log::error!("Failed to handle the creation of object: {}", e),
-                    };
-                }
-                WatchEvent::Deleted(o) => {
-                    match operator_state.on_delete(o) {
-                        Ok(_) => {}
-                        Err(e) => log::error!("Failed to handle the deletion of object: {}", e),
-                    };
-                }
-                WatchEvent::Modified(o) => {
-                    match operator_state.on_update(o) {
-                        Ok(_) => {}
-                        Err(e) => log::error!("Failed to handle the update of object: {}", e),
-                    };
-                }
-                WatchEvent::Error(e) => {
-                    if e.code == 410 {
-                        // We're desynced because nothing happened for too long. This is handled by kube I believe...    
-                    } else {
-                        log::error!("Failed to watch objects: {}", e);
-                    }
-                },
-                WatchEvent::Bookmark(_) => {
-                    log::debug!("Received bookmark. Not handled.");
+        tokio::select! {
+            ev = source.next() => match ev {
+                Some(ev) => handle_event(&mut operator_state, &filter, ev?),
+                None => return Ok(()),
+            },
+            _ = ticks.tick() => {
+                if let Err(e) = operator_state.operator.tick() {
+                    log::error!("Failed to finish the postponed work: {}", e);
                 }
             }
         }
     }
 }
 
+fn handle_event<Obj, Op, St>(
+    operator_state: &mut OperatorState<Obj, Op, St>,
+    filter: &NameFilter,
+    ev: Event<Obj>,
+) where
+    Obj: Clone + DeserializeOwned + Meta + PartialEq + std::fmt::Debug,
+    Op: Operator<Obj, St>,
+{
+    match ev {
+        Event::Added(ref o) | Event::Modified(ref o) | Event::Deleted(ref o)
+            if !filter.matches(o) =>
+        {
+            let name = key(o);
+            if operator_state.objects.contains_key(&name) {
+                // the files of a known object must not be left behind once it stops passing the filter
+                match operator_state.on_filtered_out(name) {
+                    Ok(_) => {}
+                    Err(e) => log::error!("Failed to handle the deletion of object: {}", e),
+                };
+            } else {
+                log::trace!("Ignoring object {} not passing the name filter.", name);
+            }
+        }
+        Event::Added(o) => {
+            match operator_state.on_create(o) {
+                Ok(_) => {}
+                Err(e) => log::error!("Failed to handle the creation of object: {}", e),
+            };
+        }
+        Event::Deleted(o) => {
+            match operator_state.on_delete(o) {
+                Ok(_) => {}
+                Err(e) => log::error!("Failed to handle the deletion of object: {}", e),
+            };
+        }
+        Event::Modified(o) => {
+            match operator_state.on_update(o) {
+                Ok(_) => {}
+                Err(e) => log::error!("Failed to handle the update of object: {}", e),
+            };
+        }
+        Event::Resync(namespace, objects) => {
+            operator_state.on_resync(
+                namespace.as_deref(),
+                objects.into_iter().filter(|o| filter.matches(o)).collect(),
+            );
+        }
+    }
+}
+
 // private impls
 
-type Objects<K> = std::collections::HashMap<String, K>;
+type Objects<K> = std::collections::HashMap<String, Stored<K>>;
+
+/// The prepared object along with the UID of the Kubernetes object it was prepared from. The UID tells apart objects
+/// deleted and recreated with the same name.
+struct Stored<St> {
+    uid: Option<String>,
+    state: St,
+}
+
+impl<St> Stored<St> {
+    /// Whether the other UID belongs to a different incarnation of the object. Unknown UIDs are assumed to match.
+    fn is_recreated(&self, uid: &Option<String>) -> bool {
+        match (&self.uid, uid) {
+            (Some(old), Some(new)) => old != new,
+            _ => false,
+        }
+    }
+}
+
+/// The key of the object in the internal state, so that same-named objects from different namespaces are told apart.
+pub fn key<Obj: Meta>(object: &Obj) -> String {
+    match object.namespace() {
+        Some(ns) => format!("{}/{}", ns, object.name()),
+        None => object.name(),
+    }
+}
 
 /// Internal state of the operator.
 struct OperatorState<Obj, Op, St>
@@ -97,65 +197,375 @@ where
         let objs = Objects::new();
 
         OperatorState {
-            operator: operator,
+            operator,
             objects: objs,
-            _data: std::marker::PhantomData::default()
+            _data: std::marker::PhantomData,
+        }
+    }
+
+    fn prepare(&self, object: Obj) -> Stored<St> {
+        Stored {
+            uid: object.meta().uid.clone(),
+            state: self.operator.prepare(object),
+        }
+    }
+
+    /// Lets the operator react to the object having been deleted and created again under the same name.
+    fn recreate(&mut self, name: &str, old: &Stored<St>, new: &Stored<St>) -> Result<(), Error> {
+        log::debug!(
+            "Object {} has been recreated. Handling it as deletion and creation.",
+            name
+        );
+        self.operator.reconcile(Some(&old.state), None)?;
+        self.operator.reconcile(None, Some(&new.state))?;
+        Ok(())
+    }
+
+    /// Stores the new state of the object if the operator reconciled it, or keeps the old one if it failed to, so that
+    /// the next reconciliation is computed against what has actually been applied.
+    fn keep(
+        &mut self,
+        name: String,
+        new: Option<Stored<St>>,
+        old: Option<Stored<St>>,
+        result: Result<(), Error>,
+    ) -> Result<(), Error> {
+        let kept = if result.is_ok() { new } else { old };
+        if let Some(st) = kept {
+            self.objects.insert(name, st);
         }
+        result
     }
 
     /// Updates the internal state with the newly created object and let's the operator react as well.
     fn on_create(&mut self, object: Obj) -> Result<(), Error> {
-        let name = object.name();
-        let st = self.operator.prepare(object);
-        match self.objects.insert(name.clone(), st) {
-            Some(o) => {
+        let name = key(&object);
+        let st = self.prepare(object);
+        let old = self.objects.remove(&name);
+        let result = match old {
+            Some(ref o) if o.is_recreated(&st.uid) => self.recreate(&name, o, &st),
+            Some(ref o) => {
                 log::debug!("Received create message about an object we already know. Possible recovery from timeout.");
-                self.operator.reconcile(Some(&o), Some(self.objects.get(&name).unwrap()))?;
-                Ok(())
-            },
+                self.operator.reconcile(Some(&o.state), Some(&st.state))
+            }
             None => {
                 log::debug!("Creating object: {}", name);
-                self.operator
-                    .reconcile(None, Some(self.objects.get(&name).unwrap()))?;
+                let result = self.operator.reconcile(None, Some(&st.state));
                 log::debug!("Created object: {}", name);
-                Ok(())
+                result
             }
-        }
+        };
+        self.keep(name, Some(st), old, result)
     }
 
     /// Updates the internal state with the freshly updated object and let's the operator react as well.
     fn on_update(&mut self, object: Obj) -> Result<(), Error> {
-        let name = object.name();
-        let st = self.operator.prepare(object);
-        match self.objects.insert(name.clone(), st) {
-            None => Err(Error::OperatorError(format!(
-                "Received update message about an object not in cache: {}",
-                name
-            ))),
-            Some(old) => {
+        let name = key(&object);
+        let st = self.prepare(object);
+        let old = self.objects.remove(&name);
+        let result = match old {
+            None => {
+                log::debug!("Received update message about an object not in cache. Handling it as creation: {}", name);
+                self.operator.reconcile(None, Some(&st.state))
+            }
+            Some(ref o) if o.is_recreated(&st.uid) => self.recreate(&name, o, &st),
+            Some(ref o) => {
                 log::debug!("Updating object: {}", name);
-                let new = self.objects.get(&name).unwrap();
-                self.operator.reconcile(Some(&old), Some(new))?;
+                let result = self.operator.reconcile(Some(&o.state), Some(&st.state));
                 log::debug!("Updated object: {}", name);
-                Ok(())
+                result
+            }
+        };
+        self.keep(name, Some(st), old, result)
+    }
+
+    /// Replaces the internal state with the complete list of objects in the namespace, or in all namespaces. The
+    /// objects no longer in the list are deleted, the rest is handled as creations or updates.
+    fn on_resync(&mut self, namespace: Option<&str>, objects: Vec<Obj>) {
+        let keys: HashSet<String> = objects.iter().map(key).collect();
+        let in_scope = |k: &String| match namespace {
+            Some(ns) => k.starts_with(&format!("{}/", ns)),
+            None => true,
+        };
+        let gone: Vec<String> = self
+            .objects
+            .keys()
+            .filter(|k| in_scope(k) && !keys.contains(*k))
+            .cloned()
+            .collect();
+
+        for name in gone {
+            if let Some(o) = self.objects.remove(&name) {
+                log::debug!("Deleting object missing after resync: {}", name);
+                let result = self.operator.reconcile(Some(&o.state), None);
+                if let Err(e) = self.keep(name, None, Some(o), result) {
+                    log::error!("Failed to handle the deletion of object: {}", e);
+                }
+            }
+        }
+
+        for object in objects {
+            let result = if self.objects.contains_key(&key(&object)) {
+                self.on_update(object)
+            } else {
+                self.on_create(object)
+            };
+            if let Err(e) = result {
+                log::error!("Failed to handle the resync of object: {}", e);
+            }
+        }
+    }
+
+    /// Handles the known object that no longer passes the filter as deleted, whatever the event was.
+    fn on_filtered_out(&mut self, name: String) -> Result<(), Error> {
+        match self.objects.remove(&name) {
+            None => Ok(()),
+            Some(o) => {
+                log::debug!(
+                    "Deleting object no longer passing the name filter: {}",
+                    name
+                );
+                let result = self.operator.reconcile(Some(&o.state), None);
+                self.keep(name, None, Some(o), result)
             }
         }
     }
 
     /// Updates the internal state with the freshly deleted object and let's the operator react as well.
     fn on_delete(&mut self, object: Obj) -> Result<(), Error> {
-        let name = object.name();
-        match self.objects.remove(&name.clone()) {
+        let name = key(&object);
+        let uid = object.meta().uid.clone();
+        match self.objects.remove(&name) {
             None => Err(Error::OperatorError(format!(
                 "Received deletion message about an object not in cache: {}",
                 name
             ))),
+            Some(o) if o.is_recreated(&uid) => {
+                // the deletion of the previous incarnation arrived after the creation of the current one
+                log::debug!(
+                    "Ignoring deletion of a previous incarnation of object: {}",
+                    name
+                );
+                self.objects.insert(name, o);
+                Ok(())
+            }
             Some(o) => {
                 log::debug!("Deleting object: {}", name);
-                self.operator.reconcile(Some(&o), None)?;
+                let result = self.operator.reconcile(Some(&o.state), None);
                 log::debug!("Deleted object: {}", name);
-                Ok(())
+                self.keep(name, None, Some(o), result)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use std::collections::VecDeque;
+
+    /// Records the keys, with the resource versions if known, of the reconciled objects as `old -> new`.
+    #[derive(Default)]
+    struct Recorder {
+        reconciled: Vec<String>,
+        /// The state failing the reconciliation.
+        reject: Option<String>,
+    }
+
+    impl Operator<ConfigMap, String> for Recorder {
+        fn prepare(&self, obj: ConfigMap) -> String {
+            match obj.meta().resource_version {
+                Some(ref version) => format!("{}@{}", key(&obj), version),
+                None => key(&obj),
+            }
+        }
+
+        fn reconcile(&mut self, old: Option<&String>, new: Option<&String>) -> Result<(), Error> {
+            self.reconciled.push(format!("{:?} -> {:?}", old, new));
+            match self.reject {
+                Some(ref reject) if new == Some(reject) => {
+                    Err(Error::OperatorError(format!("{} rejected", reject)))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn config_map(namespace: &str, name: &str) -> ConfigMap {
+        with_uid(namespace, name, "1")
+    }
+
+    fn with_uid(namespace: &str, name: &str, uid: &str) -> ConfigMap {
+        ConfigMap {
+            metadata: Some(ObjectMeta {
+                name: Some(name.into()),
+                namespace: Some(namespace.into()),
+                uid: Some(uid.into()),
+                ..ObjectMeta::default()
+            }),
+            ..ConfigMap::default()
+        }
+    }
+
+    #[test]
+    fn test_same_names_in_different_namespaces() {
+        let mut state = OperatorState::new(Recorder::default());
+
+        state.on_create(config_map("app", "shared")).unwrap();
+        state
+            .on_create(config_map("platform-config", "shared"))
+            .unwrap();
+        state.on_delete(config_map("app", "shared")).unwrap();
+
+        assert_eq!(
+            vec![
+                "None -> Some(\"app/shared\")",
+                "None -> Some(\"platform-config/shared\")",
+                "Some(\"app/shared\") -> None",
+            ],
+            state.operator.reconciled
+        );
+        assert_eq!(1, state.objects.len());
+    }
+
+    #[test]
+    fn test_recreation_handled_as_delete_and_create() {
+        let mut state = OperatorState::new(Recorder::default());
+
+        state.on_create(with_uid("app", "cm", "1")).unwrap();
+        // the deletion was missed, e.g. during a watch timeout
+        state.on_update(with_uid("app", "cm", "2")).unwrap();
+        // a late deletion of the first incarnation must not remove the second one
+        state.on_delete(with_uid("app", "cm", "1")).unwrap();
+
+        assert_eq!(
+            vec![
+                "None -> Some(\"app/cm\")",
+                "Some(\"app/cm\") -> None",
+                "None -> Some(\"app/cm\")",
+            ],
+            state.operator.reconciled
+        );
+        assert_eq!(
+            Some(&Some("2".to_string())),
+            state.objects.get("app/cm").map(|o| &o.uid)
+        );
+    }
+
+    #[test]
+    fn test_failed_reconciliation_keeps_the_applied_state() {
+        let mut state = OperatorState::new(Recorder {
+            reject: Some("app/cm@2".into()),
+            ..Recorder::default()
+        });
+        let version = |v: &str| {
+            let mut cm = config_map("app", "cm");
+            cm.metadata.as_mut().unwrap().resource_version = Some(v.into());
+            cm
+        };
+
+        state.on_create(version("1")).unwrap();
+        assert!(state.on_update(version("2")).is_err());
+        state.on_update(version("3")).unwrap();
+
+        assert_eq!(
+            vec![
+                "None -> Some(\"app/cm@1\")",
+                "Some(\"app/cm@1\") -> Some(\"app/cm@2\")",
+                "Some(\"app/cm@1\") -> Some(\"app/cm@3\")",
+            ],
+            state.operator.reconciled
+        );
+        assert_eq!(
+            Some(&"app/cm@3".to_string()),
+            state.objects.get("app/cm").map(|o| &o.state)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scripted_events() {
+        let mut source: VecDeque<Event<ConfigMap>> = vec![
+            Event::Added(config_map("app", "nginx")),
+            Event::Added(config_map("app", "ignored")),
+            Event::Modified(config_map("app", "nginx")),
+            Event::Added(config_map("app", "nginx-extra")),
+            Event::Added(config_map("platform-config", "nginx")),
+            Event::Resync(
+                Some("app".into()),
+                vec![config_map("app", "nginx"), config_map("app", "ignored")],
+            ),
+        ]
+        .into();
+        let filter = NameFilter::default().with_regex(Regex::new("^nginx").unwrap());
+        let mut state = OperatorState::new(Recorder::default());
+
+        while let Some(ev) = source.next().await {
+            handle_event(&mut state, &filter, ev.unwrap());
+        }
+
+        assert_eq!(
+            vec![
+                "None -> Some(\"app/nginx\")",
+                "Some(\"app/nginx\") -> Some(\"app/nginx\")",
+                "None -> Some(\"app/nginx-extra\")",
+                "None -> Some(\"platform-config/nginx\")",
+                "Some(\"app/nginx-extra\") -> None",
+                "Some(\"app/nginx\") -> Some(\"app/nginx\")",
+            ],
+            state.operator.reconciled
+        );
+        assert_eq!(2, state.objects.len());
+    }
+
+    #[test]
+    fn test_known_objects_not_passing_the_filter_deleted() {
+        let mut state = OperatorState::new(Recorder::default());
+        handle_event(
+            &mut state,
+            &NameFilter::default(),
+            Event::Added(config_map("app", "nginx")),
+        );
+        handle_event(
+            &mut state,
+            &NameFilter::default(),
+            Event::Added(config_map("app", "shared")),
+        );
+
+        let filter = NameFilter::default().with_regex(Regex::new("^ng").unwrap());
+        handle_event(
+            &mut state,
+            &filter,
+            Event::Modified(config_map("app", "shared")),
+        );
+        handle_event(
+            &mut state,
+            &filter,
+            Event::Deleted(config_map("app", "shared")),
+        );
+
+        assert_eq!(
+            vec![
+                "None -> Some(\"app/nginx\")",
+                "None -> Some(\"app/shared\")",
+                "Some(\"app/shared\") -> None",
+            ],
+            state.operator.reconciled
+        );
+        assert_eq!(1, state.objects.len());
+    }
+
+    #[test]
+    fn test_name_filter() {
+        let filter =
+            NameFilter::default().with_names(vec!["nginx".into(), "platform-config/shared".into()]);
+        assert!(filter.matches(&config_map("app", "nginx")));
+        assert!(filter.matches(&config_map("platform-config", "shared")));
+        assert!(!filter.matches(&config_map("app", "shared")));
+
+        let filter = filter.with_regex(Regex::new("^ng").unwrap());
+        assert!(filter.matches(&config_map("app", "nginx")));
+        assert!(!filter.matches(&config_map("platform-config", "shared")));
+    }
+}
@@ -0,0 +1,106 @@
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::io;
+use std::os::unix::io::RawFd;
+
+/// A file descriptor referring to a process. Unlike a PID, it can never start referring to a different process after
+/// the original process exits, so signalling through it is free of PID reuse races.
+#[derive(Debug)]
+pub struct PidFd {
+    fd: RawFd,
+    pid: Pid,
+}
+
+impl PidFd {
+    /// Opens a pidfd for the process. Returns `Ok(None)` if the kernel doesn't support pidfds.
+    pub fn open(pid: Pid) -> io::Result<Option<PidFd>> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            if is_unsupported(&err) {
+                Ok(None)
+            } else {
+                Err(err)
+            }
+        } else {
+            Ok(Some(PidFd {
+                fd: fd as RawFd,
+                pid,
+            }))
+        }
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Sends the signal to the process. Fails with `ESRCH` if the process has exited in the meantime.
+    pub fn send_signal(&self, signal: Signal) -> io::Result<()> {
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                self.fd,
+                signal as libc::c_int,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for PidFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Whether the error means that pidfds are not available, e.g. on kernels older than 5.3 or under seccomp policies
+/// that don't know the syscalls. Other errors, like `EPERM`, only concern the particular process.
+pub fn is_unsupported(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOSYS)
+}
+
+/// Whether the error means that the process has already exited.
+pub fn is_gone(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ESRCH)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Command;
+
+    #[test]
+    fn test_signal_through_pidfd() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+
+        let pidfd = match PidFd::open(pid).unwrap() {
+            Some(pidfd) => pidfd,
+            None => {
+                // nothing to test on kernels without pidfd support
+                child.kill().unwrap();
+                child.wait().unwrap();
+                return;
+            }
+        };
+
+        pidfd.send_signal(Signal::SIGTERM).unwrap();
+        let status = child.wait().unwrap();
+        assert_eq!(Some(Signal::SIGTERM as i32), status.signal());
+
+        // the process is gone and reaped, its PID could be reused but the pidfd must not signal anything
+        match pidfd.send_signal(Signal::SIGTERM) {
+            Err(ref e) if is_gone(e) => {}
+            r => panic!("Unexpected result of signalling an exited process: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use nix::unistd::Pid;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::str;
+
+pub const DEFAULT_ROOT: &str = "/proc";
+
+/// Reads the process information from a procfs mount.
+#[derive(Debug, Clone)]
+pub struct ProcFs {
+    root: PathBuf,
+}
+
+/// The fields of `/proc/<pid>/stat` we're interested in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub state: char,
+    pub ppid: i32,
+    /// The time the process started after system boot, in clock ticks.
+    pub start_time: Option<u64>,
+}
+
+impl Default for ProcFs {
+    fn default() -> Self {
+        ProcFs::new(DEFAULT_ROOT)
+    }
+}
+
+impl ProcFs {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        ProcFs { root: root.into() }
+    }
+
+    fn path(&self, pid: &Pid, file: &str) -> PathBuf {
+        let mut path = self.root.join(pid.to_string());
+        if !file.is_empty() {
+            path.push(file);
+        }
+        path
+    }
+
+    /// Lists the PIDs of all the processes in ascending order, so that scans are deterministic. Entries that don't
+    /// represent processes are skipped.
+    pub fn pids(&self) -> io::Result<Vec<Pid>> {
+        let mut pids: Vec<Pid> = fs::read_dir(&self.root)?
+            .filter_map(|r| match r {
+                Ok(e) => Some(e),
+                Err(e) => {
+                    log::trace!("Failed to read a procfs entry: {}", e);
+                    None
+                }
+            })
+            .filter_map(|e| {
+                log::trace!("Inspecting {:?}", e.path());
+                let pid = e.file_name().to_str().and_then(parse_pid)?;
+                // the process may have exited in the meantime
+                match e.file_type() {
+                    Ok(t) if t.is_dir() => Some(pid),
+                    _ => None,
+                }
+            })
+            .collect();
+        pids.sort_by_key(|p| p.as_raw());
+        Ok(pids)
+    }
+
+    /// The PID of the calling process as seen in this procfs, if it is visible there.
+    pub fn self_pid(&self) -> Option<Pid> {
+        fs::read_link(self.root.join("self"))
+            .ok()
+            .and_then(|p| p.to_str().and_then(parse_pid))
+    }
+
+    pub fn exists(&self, pid: &Pid) -> bool {
+        self.path(pid, "").exists()
+    }
+
+    /// Reads the commandline of the process with the arguments separated by spaces.
+    pub fn cmdline(&self, pid: &Pid) -> io::Result<String> {
+        Ok(parse_cmdline(&fs::read(self.path(pid, "cmdline"))?))
+    }
+
+    pub fn stat(&self, pid: &Pid) -> io::Result<Option<Stat>> {
+        Ok(parse_stat(&fs::read_to_string(self.path(pid, "stat"))?))
+    }
+
+    /// Lists the PIDs of the direct children of the process.
+    pub fn children(&self, pid: &Pid) -> io::Result<Vec<Pid>> {
+        Ok(self
+            .pids()?
+            .into_iter()
+            .filter(|p| matches!(self.stat(p), Ok(Some(ref s)) if s.ppid == pid.as_raw()))
+            .collect())
+    }
+
+    pub fn exe(&self, pid: &Pid) -> io::Result<PathBuf> {
+        fs::read_link(self.path(pid, "exe"))
+    }
+
+    pub fn comm(&self, pid: &Pid) -> io::Result<String> {
+        Ok(fs::read_to_string(self.path(pid, "comm"))?
+            .trim_end_matches('\n')
+            .to_string())
+    }
+
+    /// Reads the control groups the process belongs to, one per line.
+    pub fn cgroup(&self, pid: &Pid) -> io::Result<String> {
+        fs::read_to_string(self.path(pid, "cgroup"))
+    }
+
+    /// The effective UID of the owner of the process.
+    pub fn uid(&self, pid: &Pid) -> io::Result<u32> {
+        Ok(fs::metadata(self.path(pid, ""))?.uid())
+    }
+}
+
+/// Parses the name of a procfs entry as a PID. Only positive numbers are PIDs.
+fn parse_pid(name: &str) -> Option<Pid> {
+    match name.parse::<i32>() {
+        Ok(pid) if pid > 0 => Some(Pid::from_raw(pid)),
+        _ => None,
+    }
+}
+
+fn parse_cmdline(bytes: &[u8]) -> String {
+    // the cmdline is \0 separated, so we need to convert
+    let cmdline =
+        bytes
+            .split(|b| *b == 0)
+            .map(|a| str::from_utf8(a))
+            .fold(String::new(), |mut acc, s| {
+                if !acc.is_empty() {
+                    acc.push(' ');
+                }
+                acc.push_str(s.unwrap_or(""));
+                acc
+            });
+    cmdline.trim().to_string()
+}
+
+pub fn parse_stat(stat: &str) -> Option<Stat> {
+    // the executable name can contain anything, including parens and spaces, so we look for the last paren
+    let last_paren = stat.rfind(") ")?;
+    let mut splits = stat.split_at(last_paren + 2).1.split(' ');
+    let state = splits.next()?.chars().next()?;
+    let ppid = splits.next()?;
+    // the start time is the 22nd field, i.e. the 20th after the executable name
+    let start_time = splits.nth(17).and_then(|t| t.parse::<u64>().ok());
+    match ppid.parse::<i32>() {
+        Ok(ppid) => Some(Stat {
+            state,
+            ppid,
+            start_time,
+        }),
+        Err(e) => {
+            log::error!("Could not parse ppid {} as a number, weird: {}", ppid, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pids_in_full_range() {
+        let root = tempfile::tempdir().unwrap();
+        for pid in &["1", "42", "65536", "4194304"] {
+            fs::create_dir(root.path().join(pid)).unwrap();
+        }
+        for entry in &["self", "sys", "-5", "0", "12abc"] {
+            fs::create_dir(root.path().join(entry)).unwrap();
+        }
+        fs::write(root.path().join("1000"), "not a process").unwrap();
+
+        let pids: Vec<i32> = ProcFs::new(root.path())
+            .pids()
+            .unwrap()
+            .iter()
+            .map(|p| p.as_raw())
+            .collect();
+
+        assert_eq!(vec![1, 42, 65536, 4194304], pids);
+    }
+
+    #[test]
+    fn test_cmdline_parsing() {
+        assert_eq!(
+            "nginx -g daemon off;",
+            parse_cmdline(b"nginx\0-g\0daemon off;\0")
+        );
+        assert_eq!("", parse_cmdline(b""));
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+/// Limits how often an action can happen. The action needs to be at least the minimum interval apart from the
+/// previous one and, if a token bucket is configured, there needs to be a token available in the bucket. The bucket
+/// starts full and gets a new token each refill interval.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    min_interval: Duration,
+    bucket: Option<TokenBucket>,
+    last: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    capacity: u32,
+    refill_interval: Duration,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Parses the capacity of a token bucket. The bucket needs to hold at least 1 token, otherwise nothing would ever be
+/// allowed.
+pub fn parse_capacity(capacity: &str) -> Result<u32, String> {
+    match capacity.parse::<u32>() {
+        Ok(0) => Err("The capacity needs to be at least 1.".into()),
+        Ok(capacity) => Ok(capacity),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+impl TokenBucket {
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        let new_tokens = if self.refill_interval.as_nanos() == 0 {
+            f64::from(self.capacity)
+        } else {
+            elapsed.as_secs_f64() / self.refill_interval.as_secs_f64()
+        };
+
+        f64::min(f64::from(self.capacity), self.tokens + new_tokens)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(min_interval: Duration) -> Self {
+        RateLimiter {
+            min_interval,
+            bucket: None,
+            last: None,
+        }
+    }
+
+    /// Additionally limits the action by a token bucket of the provided capacity.
+    pub fn with_token_bucket(mut self, capacity: u32, refill_interval: Duration) -> Self {
+        self.bucket = Some(TokenBucket {
+            capacity,
+            refill_interval,
+            tokens: f64::from(capacity),
+            refilled_at: Instant::now(),
+        });
+        self
+    }
+
+    /// Checks whether the action would be allowed at the given time.
+    pub fn ready(&self, now: Instant) -> bool {
+        let interval_passed = match self.last {
+            Some(last) => now.saturating_duration_since(last) >= self.min_interval,
+            None => true,
+        };
+
+        interval_passed
+            && self
+                .bucket
+                .as_ref()
+                .map(|b| b.tokens_at(now) >= 1.0)
+                .unwrap_or(true)
+    }
+
+    /// Records the action at the given time if it is allowed. Returns whether it is.
+    pub fn acquire(&mut self, now: Instant) -> bool {
+        if !self.ready(now) {
+            return false;
+        }
+
+        if let Some(ref mut bucket) = self.bucket {
+            bucket.tokens = bucket.tokens_at(now) - 1.0;
+            bucket.refilled_at = now;
+        }
+        self.last = Some(now);
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_min_interval() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(Duration::from_secs(10));
+
+        assert!(limiter.acquire(start));
+        assert!(!limiter.acquire(start + Duration::from_secs(5)));
+        assert!(limiter.acquire(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_capacity_parsing() {
+        assert_eq!(Ok(3), parse_capacity("3"));
+        assert!(parse_capacity("0").is_err());
+        assert!(parse_capacity("-1").is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut limiter =
+            RateLimiter::new(Duration::from_secs(0)).with_token_bucket(2, Duration::from_secs(60));
+
+        assert!(limiter.acquire(start));
+        assert!(limiter.acquire(start));
+        assert!(!limiter.acquire(start + Duration::from_secs(30)));
+        assert!(limiter.ready(start + Duration::from_secs(60)));
+        assert!(limiter.acquire(start + Duration::from_secs(60)));
+        assert!(!limiter.acquire(start + Duration::from_secs(61)));
+    }
+}
@@ -0,0 +1,349 @@
+use super::bumper::Bumper;
+use super::health;
+use nix::unistd::Pid;
+use regex::Regex;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Error)]
+pub enum Error {
+    #[error("Invalid reload check configuration: {0}")]
+    ConfigError(String),
+
+    #[error("Reload not detected: {0}")]
+    NotReloaded(String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// How long a single config hash request can take. The checks run from the reconciliation and the ticks of the
+/// operator, so a process that doesn't answer must not hold them up for the whole timeout of the verification; the
+/// request is just made again in the next tick.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A single way of telling that the bumped process picked up the new configuration.
+#[derive(Debug, Clone)]
+pub enum ReloadCheck {
+    /// The process needs to have been restarted, i.e. the process found after the bump needs to have a different
+    /// start time than the one found before it.
+    StartTimeChanged,
+    /// None of the children of the process found before the bump, e.g. nginx workers, may be among its children
+    /// after it and there needs to be at least 1 child.
+    WorkersReplaced,
+    /// A line matching the regex needs to be appended to the log file after the bump.
+    LogLine(PathBuf, Regex),
+    /// The body returned from the plain-HTTP URL, e.g. a hash of the loaded configuration, needs to change.
+    ConfigHash(String),
+}
+
+/// What a check observed before the bump.
+#[derive(Debug, Clone)]
+enum Observation {
+    StartTime(Option<(Pid, u64)>),
+    Workers(Vec<Pid>),
+    LogSize(u64),
+    ConfigHash(Option<String>),
+}
+
+/// The state of the process before the bump, to compare with the state after it.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    observations: Vec<Observation>,
+}
+
+/// A verification of the reload after a bump, polled until all the checks passed or the timeout elapsed.
+#[derive(Debug, Clone)]
+pub struct Verification {
+    /// The checks that haven't passed yet, along with what they observed before the bump.
+    pending: Vec<(ReloadCheck, Observation)>,
+    deadline: Instant,
+}
+
+/// Verifies that the bumped process actually reloaded its configuration. All the configured checks need to pass
+/// within the timeout for the reload to be considered successful.
+#[derive(Debug, Clone)]
+pub struct ReloadVerifier {
+    checks: Vec<ReloadCheck>,
+    timeout: Duration,
+}
+
+impl ReloadVerifier {
+    pub fn new(checks: Vec<ReloadCheck>, timeout: Duration) -> Result<Self> {
+        if checks.is_empty() {
+            return Err(Error::ConfigError(
+                "At least 1 reload check needs to be defined.".into(),
+            ));
+        }
+
+        for check in &checks {
+            if let ReloadCheck::ConfigHash(ref url) = check {
+                health::parse_url(url).map_err(|e| Error::ConfigError(format!("{}", e)))?;
+            }
+        }
+
+        Ok(ReloadVerifier { checks, timeout })
+    }
+
+    /// Observes the state of the process before the bump. The config hash request can take at most
+    /// [REQUEST_TIMEOUT](REQUEST_TIMEOUT).
+    pub fn snapshot(&self, mut bumper: Option<&mut Bumper>) -> Snapshot {
+        let observations = self
+            .checks
+            .iter()
+            .map(|check| match check {
+                ReloadCheck::StartTimeChanged => {
+                    Observation::StartTime(bumper.as_mut().and_then(|b| start_time(b)))
+                }
+                ReloadCheck::WorkersReplaced => {
+                    Observation::Workers(bumper.as_mut().map(|b| workers(b)).unwrap_or_default())
+                }
+                ReloadCheck::LogLine(ref file, _) => {
+                    Observation::LogSize(fs::metadata(file).map(|m| m.len()).unwrap_or(0))
+                }
+                ReloadCheck::ConfigHash(ref url) => {
+                    Observation::ConfigHash(config_hash(url, REQUEST_TIMEOUT).ok())
+                }
+            })
+            .collect();
+
+        Snapshot { observations }
+    }
+
+    /// Starts the verification of the bump done after the snapshot. The checks need to pass within the timeout.
+    pub fn start(&self, before: Snapshot) -> Verification {
+        Verification {
+            pending: self
+                .checks
+                .iter()
+                .cloned()
+                .zip(before.observations)
+                .collect(),
+            deadline: Instant::now() + self.timeout,
+        }
+    }
+
+    /// Runs the checks of the verification that haven't passed yet. Returns true if all the checks passed, false if
+    /// some are still pending and an error once the timeout elapsed without all of them passing. A single poll doesn't
+    /// wait for the timeout: the config hash request can take at most [REQUEST_TIMEOUT](REQUEST_TIMEOUT), or the time
+    /// left until the timeout if that's shorter.
+    pub fn poll(
+        &self,
+        verification: &mut Verification,
+        mut bumper: Option<&mut Bumper>,
+    ) -> Result<bool> {
+        let deadline = verification.deadline;
+        let mut failures = vec![];
+        verification.pending.retain(|(check, observation)| {
+            match self.check(check, observation, bumper.as_deref_mut(), deadline) {
+                Ok(_) => {
+                    log::debug!("Reload check {:?} passed.", check);
+                    false
+                }
+                Err(reason) => {
+                    failures.push(reason);
+                    true
+                }
+            }
+        });
+
+        if verification.pending.is_empty() {
+            Ok(true)
+        } else if Instant::now() >= deadline {
+            Err(Error::NotReloaded(failures.join(", ")))
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Runs a single check against its observation before the bump. Returns the reason of the failure, if any.
+    fn check(
+        &self,
+        check: &ReloadCheck,
+        before: &Observation,
+        bumper: Option<&mut Bumper>,
+        deadline: Instant,
+    ) -> std::result::Result<(), String> {
+        match (check, before) {
+            (ReloadCheck::StartTimeChanged, Observation::StartTime(before)) => {
+                match bumper.and_then(start_time) {
+                    Some(now) if Some(now) != *before => Ok(()),
+                    Some(_) => Err("the process has not been restarted".into()),
+                    None => Err("the process is not running".into()),
+                }
+            }
+            (ReloadCheck::WorkersReplaced, Observation::Workers(before)) => {
+                let now = bumper.map(workers).unwrap_or_default();
+                if now.is_empty() {
+                    Err("the process has no workers".into())
+                } else if now.iter().any(|w| before.contains(w)) {
+                    Err("the workers of the process have not been replaced".into())
+                } else {
+                    Ok(())
+                }
+            }
+            (ReloadCheck::LogLine(ref file, ref regex), Observation::LogSize(size)) => {
+                match appended_lines(file, *size) {
+                    Ok(lines) if lines.iter().any(|l| regex.is_match(l)) => Ok(()),
+                    Ok(_) => Err(format!("no line matching `{}` in {:?}", regex, file)),
+                    Err(e) => Err(format!("failed to read {:?}: {}", file, e)),
+                }
+            }
+            (ReloadCheck::ConfigHash(ref url), Observation::ConfigHash(before)) => {
+                let timeout = deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(REQUEST_TIMEOUT);
+                match config_hash(url, timeout) {
+                    Ok(now) if Some(&now) != before.as_ref() => Ok(()),
+                    Ok(now) => Err(format!("the config hash at {} is still `{}`", url, now)),
+                    Err(e) => Err(e),
+                }
+            }
+            (check, observation) => Err(format!(
+                "{:?} can't be verified using {:?}",
+                check, observation
+            )),
+        }
+    }
+}
+
+/// The PID and start time of the process to bump.
+fn start_time(bumper: &mut Bumper) -> Option<(Pid, u64)> {
+    let pid = bumper.target_pid()?;
+    match bumper.proc().stat(&pid) {
+        Ok(Some(stat)) => stat.start_time.map(|t| (pid, t)),
+        _ => None,
+    }
+}
+
+/// The children of the process to bump.
+fn workers(bumper: &mut Bumper) -> Vec<Pid> {
+    match bumper.target_pid() {
+        Some(pid) => bumper.proc().children(&pid).unwrap_or_else(|e| {
+            log::warn!("Failed to list the children of process {}: {}", pid, e);
+            vec![]
+        }),
+        None => vec![],
+    }
+}
+
+/// Reads the lines appended to the file since it had the provided size. If the file is smaller than that, it is
+/// assumed to have been rotated and is read whole.
+fn appended_lines(file: &Path, since: u64) -> std::io::Result<Vec<String>> {
+    let mut f = fs::File::open(file)?;
+    let len = f.metadata()?.len();
+    f.seek(SeekFrom::Start(if len < since { 0 } else { since }))?;
+
+    let mut data = vec![];
+    f.read_to_end(&mut data)?;
+
+    Ok(String::from_utf8_lossy(&data)
+        .lines()
+        .map(|l| l.to_owned())
+        .collect())
+}
+
+fn config_hash(url: &str, timeout: Duration) -> std::result::Result<String, String> {
+    if timeout == Duration::from_secs(0) {
+        return Err(format!("{}: timed out", url));
+    }
+
+    match health::http_get(url, timeout) {
+        Ok((status, body)) if (200..300).contains(&status) => Ok(body.trim().to_owned()),
+        Ok((status, _)) => Err(format!("{}: HTTP status {}", url, status)),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_log_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("nginx.log");
+        fs::write(&log, "signal process started\n").unwrap();
+
+        let verifier = ReloadVerifier::new(
+            vec![ReloadCheck::LogLine(
+                log.clone(),
+                Regex::new("signal process started").unwrap(),
+            )],
+            Duration::from_millis(100),
+        )
+        .unwrap();
+
+        let before = verifier.snapshot(None);
+        let mut verification = verifier.start(before.clone());
+        assert!(!verifier.poll(&mut verification, None).unwrap());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(verifier.poll(&mut verification, None).is_err());
+
+        let mut verification = verifier.start(before);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&log)
+            .unwrap()
+            .write_all(b"reconfiguring\nsignal process started\n")
+            .unwrap();
+        assert!(verifier.poll(&mut verification, None).unwrap());
+    }
+
+    #[test]
+    fn test_config_hash() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/config-hash",
+            listener.local_addr().unwrap().port()
+        );
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                let hash = if i < 2 { "abc" } else { "def" };
+                let _ = write!(stream, "HTTP/1.1 200 OK\r\n\r\n{}\n", hash);
+            }
+        });
+
+        let verifier =
+            ReloadVerifier::new(vec![ReloadCheck::ConfigHash(url)], Duration::from_secs(10))
+                .unwrap();
+
+        let before = verifier.snapshot(None);
+        let mut verification = verifier.start(before);
+        // the 2nd request returns the same hash
+        assert!(!verifier.poll(&mut verification, None).unwrap());
+        // the 3rd one a new one
+        assert!(verifier.poll(&mut verification, None).unwrap());
+    }
+
+    #[test]
+    fn test_config_hash_poll_does_not_wait_for_the_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/config-hash",
+            listener.local_addr().unwrap().port()
+        );
+        // accepts the connections, but never answers
+        std::thread::spawn(move || {
+            let streams: Vec<_> = listener.incoming().collect();
+            drop(streams);
+        });
+
+        let verifier =
+            ReloadVerifier::new(vec![ReloadCheck::ConfigHash(url)], Duration::from_secs(10))
+                .unwrap();
+
+        let started = Instant::now();
+        let before = verifier.snapshot(None);
+        let mut verification = verifier.start(before);
+        assert!(!verifier.poll(&mut verification, None).unwrap());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
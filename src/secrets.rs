@@ -0,0 +1,338 @@
+use super::operator::{self, key, NameFilter};
+use super::source::{Backoff, Event, EventSource, KubeSource};
+use super::template::{self, Renderer};
+use futures::future::BoxFuture;
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use kube::api::{Api, ListParams};
+use kube::Client;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// The decoded values of the secrets referenced from the config map templates, shared between the
+/// [SecretsSource](SecretsSource) keeping it up to date and the [Renderer](Renderer). The values are never logged,
+/// not even in the debug output.
+#[derive(Clone, Default)]
+pub struct SecretStore {
+    secrets: Arc<RwLock<BTreeMap<String, BTreeMap<String, String>>>>,
+}
+
+impl std::fmt::Debug for SecretStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<String> = self
+            .secrets
+            .read()
+            .map(|s| s.keys().cloned().collect())
+            .unwrap_or_default();
+        f.debug_struct("SecretStore")
+            .field("secrets", &names)
+            .finish()
+    }
+}
+
+impl SecretStore {
+    /// The value of the key of the secret, if known.
+    pub fn get(&self, namespace: &str, name: &str, key: &str) -> Option<String> {
+        self.secrets
+            .read()
+            .ok()?
+            .get(&format!("{}/{}", namespace, name))?
+            .get(key)
+            .cloned()
+    }
+
+    /// Replaces the data of the secret, `None` meaning the secret doesn't exist. Returns whether the data changed.
+    pub fn update(
+        &self,
+        namespace: &str,
+        name: &str,
+        data: Option<BTreeMap<String, String>>,
+    ) -> bool {
+        let mut secrets = match self.secrets.write() {
+            Ok(secrets) => secrets,
+            Err(_) => return false,
+        };
+
+        let key = format!("{}/{}", namespace, name);
+        match data {
+            Some(data) => secrets.insert(key, data.clone()) != Some(data),
+            None => secrets.remove(&key).is_some(),
+        }
+    }
+}
+
+/// The namespace and name of a secret.
+type SecretRef = (String, String);
+
+/// Decodes the data of the secret. The keys with values that are not valid UTF-8 are left out.
+fn decode(secret: &Secret) -> BTreeMap<String, String> {
+    let mut data = BTreeMap::new();
+
+    for (k, v) in secret.data.iter().flatten() {
+        match String::from_utf8(v.0.clone()) {
+            Ok(v) => {
+                data.insert(k.clone(), v);
+            }
+            Err(_) => log::warn!(
+                "Ignoring key `{}` of secret `{}` that is not valid UTF-8.",
+                k,
+                key(secret)
+            ),
+        }
+    }
+    for (k, v) in secret.string_data.iter().flatten() {
+        data.insert(k.clone(), v.clone());
+    }
+
+    data
+}
+
+/// Passes through the events about the config maps, keeping the [SecretStore](SecretStore) up to date with the
+/// secrets the config map templates refer to. A config map referring to a secret not seen before is only passed on
+/// once the secret has been read, so that it can be rendered right away. The referenced secrets are watched from then
+/// on and a change of a secret results in a modification of the config maps referring to it, so that they are
+/// rendered again.
+pub struct SecretsSource<Src> {
+    inner: Src,
+    client: Client,
+    store: SecretStore,
+    filter: NameFilter,
+    /// The config maps referring to secrets, with the keys of the referenced secrets, by the keys of the config maps.
+    config_maps: BTreeMap<String, (ConfigMap, BTreeSet<String>)>,
+    /// The keys of the secrets being watched.
+    watched: BTreeSet<String>,
+    /// The event waiting for the secrets, given by the namespace and name, to be read before it is passed on.
+    fetching: Option<(Event<ConfigMap>, Vec<SecretRef>)>,
+    pending: VecDeque<Event<ConfigMap>>,
+    changed_tx: UnboundedSender<String>,
+    changed: UnboundedReceiver<String>,
+}
+
+impl<Src: EventSource<ConfigMap> + Send> SecretsSource<Src> {
+    pub fn new(inner: Src, client: Client, store: SecretStore) -> Self {
+        let (changed_tx, changed) = mpsc::unbounded_channel();
+        SecretsSource {
+            inner,
+            client,
+            store,
+            filter: NameFilter::default(),
+            config_maps: BTreeMap::new(),
+            watched: BTreeSet::new(),
+            fetching: None,
+            pending: VecDeque::new(),
+            changed_tx,
+            changed,
+        }
+    }
+
+    /// Only tracks the secrets referred to by the config maps passing the filter.
+    pub fn with_filter(mut self, filter: NameFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Updates the references to the secrets and returns the namespaces and names of the secrets not watched yet.
+    fn track(&mut self, ev: &Event<ConfigMap>) -> Vec<SecretRef> {
+        match ev {
+            Event::Added(cm) | Event::Modified(cm) => self.track_config_map(cm),
+            Event::Deleted(cm) => {
+                self.config_maps.remove(&key(cm));
+                vec![]
+            }
+            Event::Resync(namespace, cms) => {
+                let prefix = namespace.as_ref().map(|ns| format!("{}/", ns));
+                self.config_maps.retain(|k, _| match prefix {
+                    Some(ref prefix) => !k.starts_with(prefix),
+                    None => false,
+                });
+                cms.iter()
+                    .flat_map(|cm| self.track_config_map(cm))
+                    .collect()
+            }
+        }
+    }
+
+    fn track_config_map(&mut self, cm: &ConfigMap) -> Vec<SecretRef> {
+        let cm_key = key(cm);
+        let metadata = cm.metadata.as_ref();
+        let namespace = metadata.and_then(|m| m.namespace.as_ref());
+        let enabled = metadata
+            .and_then(|m| m.annotations.as_ref())
+            .map(Renderer::is_enabled)
+            .unwrap_or(false);
+
+        let namespace = match namespace {
+            Some(ns) if enabled && self.filter.matches(cm) => ns,
+            _ => {
+                self.config_maps.remove(&cm_key);
+                return vec![];
+            }
+        };
+
+        let names: BTreeSet<String> = cm
+            .data
+            .iter()
+            .flat_map(|data| data.values())
+            .flat_map(|v| template::secret_references(v))
+            .collect();
+        if names.is_empty() {
+            self.config_maps.remove(&cm_key);
+            return vec![];
+        }
+
+        let missing = names
+            .iter()
+            .filter(|name| !self.watched.contains(&format!("{}/{}", namespace, name)))
+            .map(|name| (namespace.clone(), name.clone()))
+            .collect();
+
+        let secrets = names
+            .into_iter()
+            .map(|name| format!("{}/{}", namespace, name))
+            .collect();
+        self.config_maps.insert(cm_key, (cm.clone(), secrets));
+
+        missing
+    }
+
+    /// Reads the secret and starts watching it. A secret that doesn't exist yet is watched as well, so that its
+    /// creation is noticed. A secret that fails to be read otherwise is not watched, it is read again once a config
+    /// map referring to it changes.
+    async fn fetch(&mut self, namespace: &str, name: &str) {
+        let secret_key = format!("{}/{}", namespace, name);
+        if self.watched.contains(&secret_key) {
+            return;
+        }
+
+        let api: Api<Secret> = Api::namespaced(self.client.clone(), namespace);
+        match api.get(name).await {
+            Ok(secret) => {
+                self.store.update(namespace, name, Some(decode(&secret)));
+            }
+            Err(kube::Error::Api(ref e)) if e.code == 404 => {
+                log::warn!("Secret `{}` doesn't exist.", secret_key);
+            }
+            Err(e) => {
+                log::warn!("Failed to read secret `{}`: {}", secret_key, e);
+                return;
+            }
+        }
+
+        log::info!(
+            "Watching secret `{}` referenced from templates.",
+            secret_key
+        );
+        tokio::spawn(watch(
+            self.client.clone(),
+            namespace.to_owned(),
+            name.to_owned(),
+            self.store.clone(),
+            self.changed_tx.clone(),
+        ));
+        self.watched.insert(secret_key);
+    }
+
+    /// Queues the modifications of the config maps referring to the changed secret.
+    fn on_secret_changed(&mut self, secret_key: &str) {
+        for (cm, secrets) in self.config_maps.values() {
+            if secrets.contains(secret_key) {
+                log::debug!(
+                    "Rendering config map `{}` again because secret `{}` changed.",
+                    key(cm),
+                    secret_key
+                );
+                self.pending.push_back(Event::Modified(cm.clone()));
+            }
+        }
+    }
+}
+
+impl<Src: EventSource<ConfigMap> + Send> EventSource<ConfigMap> for SecretsSource<Src> {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Event<ConfigMap>, operator::Error>>> {
+        Box::pin(async move {
+            loop {
+                if let Some(ev) = self.pending.pop_front() {
+                    return Some(Ok(ev));
+                }
+
+                // the progress is kept in self, so that it is resumed if this future is dropped
+                if self.fetching.is_some() {
+                    let next = self
+                        .fetching
+                        .as_ref()
+                        .and_then(|(_, missing)| missing.last().cloned());
+                    match next {
+                        Some((namespace, name)) => {
+                            self.fetch(&namespace, &name).await;
+                            if let Some((_, missing)) = self.fetching.as_mut() {
+                                missing.pop();
+                            }
+                        }
+                        None => return self.fetching.take().map(|(ev, _)| Ok(ev)),
+                    }
+                    continue;
+                }
+
+                tokio::select! {
+                    ev = self.inner.next() => match ev {
+                        Some(Ok(ev)) => {
+                            let missing = self.track(&ev);
+                            if missing.is_empty() {
+                                return Some(Ok(ev));
+                            }
+                            self.fetching = Some((ev, missing));
+                        }
+                        other => return other,
+                    },
+                    Some(secret_key) = self.changed.recv() => self.on_secret_changed(&secret_key),
+                }
+            }
+        })
+    }
+}
+
+/// Keeps the secret in the store up to date and reports its changes to the channel. A failed watch is started again
+/// after a backoff, so that the secret doesn't stop being updated.
+async fn watch(
+    client: Client,
+    namespace: String,
+    name: String,
+    store: SecretStore,
+    changed: UnboundedSender<String>,
+) {
+    let secret_key = format!("{}/{}", namespace, name);
+    let params = ListParams::default().fields(&format!("metadata.name={}", name));
+    let mut backoff = Backoff::default();
+
+    loop {
+        let mut source = KubeSource::new(
+            vec![(
+                Some(namespace.clone()),
+                Api::<Secret>::namespaced(client.clone(), &namespace),
+            )],
+            params.clone(),
+        );
+
+        while let Some(ev) = source.next().await {
+            let data = match ev {
+                Ok(Event::Added(secret)) | Ok(Event::Modified(secret)) => Some(decode(&secret)),
+                Ok(Event::Deleted(_)) => None,
+                Ok(Event::Resync(_, secrets)) => secrets.first().map(decode),
+                Err(e) => {
+                    log::error!("Failed to watch secret `{}`, retrying: {}", secret_key, e);
+                    break;
+                }
+            };
+            backoff.reset();
+
+            if store.update(&namespace, &name, data) {
+                log::info!("Secret `{}` changed.", secret_key);
+                if changed.send(secret_key.clone()).is_err() {
+                    return;
+                }
+            }
+        }
+
+        backoff.wait().await;
+    }
+}
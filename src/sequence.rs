@@ -0,0 +1,177 @@
+use nix::sys::signal::Signal;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Separates the steps in a sequence.
+pub const STEP_SEPARATOR: char = ';';
+
+#[derive(Debug, Clone, Error)]
+#[error("Invalid signal sequence `{spec}`: {reason}")]
+pub struct Error {
+    spec: String,
+    reason: String,
+}
+
+/// The process(es) a step of a sequence sends its signal to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    /// The process as currently detected, i.e. detected again if it has been replaced by an earlier step.
+    Process,
+    /// The process as detected at the beginning of the sequence, e.g. the old master after a binary upgrade.
+    Initial,
+    /// The direct children of the process detected at the beginning of the sequence.
+    Children,
+}
+
+/// A single step of a signal sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub signal: Signal,
+    pub target: Target,
+    /// How long to wait before sending the signal.
+    pub delay: Duration,
+    /// If set, wait at most this long for the targets to exit after sending the signal. If they do, the rest of
+    /// the sequence is skipped; if they don't, the sequence escalates to the next step.
+    pub wait_for_exit: Option<Duration>,
+}
+
+impl Step {
+    pub fn new(signal: Signal) -> Self {
+        Step {
+            signal,
+            target: Target::Process,
+            delay: Duration::from_secs(0),
+            wait_for_exit: None,
+        }
+    }
+}
+
+/// Parses a single step in the form `SIGNAL[@process|initial|children][,delay=DURATION][,wait=DURATION]`, where
+/// the durations are in seconds unless suffixed by `ms`. E.g. `SIGTERM,wait=10` or `SIGQUIT@initial,delay=500ms`.
+impl FromStr for Step {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let err = |reason: String| Error {
+            spec: spec.to_owned(),
+            reason,
+        };
+
+        let mut parts = spec.split(',').map(|p| p.trim());
+
+        let (signal, target) = match parts.next() {
+            Some(first) => match first.find('@') {
+                Some(idx) => (&first[..idx], Some(&first[idx + 1..])),
+                None => (first, None),
+            },
+            None => return Err(err("no signal specified".into())),
+        };
+
+        let mut step = Step::new(Signal::from_str(signal).map_err(|e| err(e.to_string()))?);
+
+        step.target = match target {
+            None | Some("process") => Target::Process,
+            Some("initial") => Target::Initial,
+            Some("children") => Target::Children,
+            Some(t) => return Err(err(format!("unknown target `{}`", t))),
+        };
+
+        for part in parts {
+            let mut kv = part.splitn(2, '=');
+            let key = kv.next().unwrap_or_default();
+            let value = kv
+                .next()
+                .ok_or_else(|| err(format!("missing value of `{}`", key)))?;
+            let duration = parse_duration(value).map_err(err)?;
+            match key {
+                "delay" => step.delay = duration,
+                "wait" => step.wait_for_exit = Some(duration),
+                _ => return Err(err(format!("unknown option `{}`", key))),
+            }
+        }
+
+        Ok(step)
+    }
+}
+
+/// Parses the steps of a sequence separated by `;`. A single signal name is a valid sequence.
+pub fn parse(spec: &str) -> Result<Vec<Step>, Error> {
+    let steps = spec
+        .split(STEP_SEPARATOR)
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(Step::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if steps.is_empty() {
+        Err(Error {
+            spec: spec.to_owned(),
+            reason: "at least 1 step needs to be defined".into(),
+        })
+    } else {
+        Ok(steps)
+    }
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, millis) = match value.strip_suffix("ms") {
+        Some(n) => (n, true),
+        None => (value.strip_suffix('s').unwrap_or(value), false),
+    };
+
+    let number = number
+        .parse::<u64>()
+        .map_err(|e| format!("invalid duration `{}`: {}", value, e))?;
+
+    Ok(if millis {
+        Duration::from_millis(number)
+    } else {
+        Duration::from_secs(number)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_single_signal() {
+        assert_eq!(vec![Step::new(Signal::SIGHUP)], parse("SIGHUP").unwrap());
+    }
+
+    #[test]
+    fn test_escalation() {
+        let steps = parse("SIGTERM,wait=10; SIGKILL").unwrap();
+        assert_eq!(
+            vec![
+                Step {
+                    wait_for_exit: Some(Duration::from_secs(10)),
+                    ..Step::new(Signal::SIGTERM)
+                },
+                Step::new(Signal::SIGKILL)
+            ],
+            steps
+        );
+    }
+
+    #[test]
+    fn test_binary_upgrade() {
+        let steps =
+            parse("SIGUSR2@initial;SIGWINCH@initial,delay=500ms;SIGQUIT@initial,delay=2s").unwrap();
+        assert_eq!(3, steps.len());
+        assert!(steps.iter().all(|s| s.target == Target::Initial));
+        assert_eq!(Duration::from_millis(500), steps[1].delay);
+        assert_eq!(Duration::from_secs(2), steps[2].delay);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(parse("").is_err());
+        assert!(parse("SIGNOPE").is_err());
+        assert!(parse("SIGHUP@nobody").is_err());
+        assert!(parse("SIGHUP,delay").is_err());
+        assert!(parse("SIGHUP,timeout=1").is_err());
+        assert!(parse("SIGHUP,wait=soon").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// How often the running command is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs the command and collects its output, like [Command::output](Command::output), but for at most the timeout.
+/// The command runs in its own process group, which is killed when the timeout expires, so that the processes started
+/// by e.g. `sh -c` don't outlive it. Fails with [TimedOut](io::ErrorKind::TimedOut) then.
+pub fn output(command: &mut Command, timeout: Duration) -> io::Result<Output> {
+    let deadline = Instant::now() + timeout;
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+
+    let group = Pid::from_raw(child.id() as i32);
+    let timed_out = || {
+        let _ = killpg(group, Signal::SIGKILL);
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out after {:?}", timeout),
+        )
+    };
+
+    // the pipes are drained meanwhile, so that the command doesn't block on writing its output
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let e = timed_out();
+            let _ = child.wait();
+            return Err(e);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    // the processes left behind by the command may still hold the pipes open
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+    let stdout = stdout.recv_timeout(remaining()).map_err(|_| timed_out())?;
+    let stderr = stderr.recv_timeout(remaining()).map_err(|_| timed_out())?;
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut output = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        let _ = tx.send(output);
+    });
+    rx
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_output_within_timeout() {
+        let output = output(
+            Command::new("sh").arg("-c").arg("echo out; echo err >&2"),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(b"out\n".to_vec(), output.stdout);
+        assert_eq!(b"err\n".to_vec(), output.stderr);
+    }
+
+    #[test]
+    fn test_hanging_command_killed() {
+        let started = Instant::now();
+        let result = output(
+            Command::new("sh").arg("-c").arg("sleep 30; echo done"),
+            Duration::from_millis(200),
+        );
+        match result {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
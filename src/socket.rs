@@ -0,0 +1,507 @@
+use super::ratelimit::RateLimiter;
+use super::updater::ConfigFile;
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Replaced by the absolute path of the changed file.
+pub const FILE_PLACEHOLDER: &str = "{file}";
+
+/// Replaced by the name of the changed file, i.e. the key in the config map.
+pub const NAME_PLACEHOLDER: &str = "{name}";
+
+/// If present, the command is sent once for each non-empty line of the changed file with the placeholder replaced by
+/// the line.
+pub const LINE_PLACEHOLDER: &str = "{line}";
+
+/// Separates the glob pattern of the files a command is sent for from the command, e.g. `*.map => add map {file} {line}`.
+pub const GLOB_SEPARATOR: &str = "=>";
+
+#[derive(Debug, Clone, Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    #[error("Initialization error: {0}")]
+    InitError(String),
+
+    #[error("Socket communication error: {0}")]
+    SocketError(String),
+
+    #[error("Command `{0}` failed: {1}")]
+    CommandError(String, String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A command template, sent only for the files matching the glob if there is one.
+#[derive(Debug, Clone)]
+struct Command {
+    files: Option<GlobMatcher>,
+    template: String,
+}
+
+impl Command {
+    /// Parses the template optionally prefixed by the glob, e.g. `*.map => add map {file} {line}`.
+    fn parse(command: &str) -> Result<Self> {
+        match command.find(GLOB_SEPARATOR) {
+            Some(idx) => {
+                let glob = command[..idx].trim();
+                let matcher = Glob::new(glob)
+                    .map_err(|e| Error::InitError(format!("Invalid glob `{}`: {}", glob, e)))?
+                    .compile_matcher();
+                Ok(Command {
+                    files: Some(matcher),
+                    template: command[idx + GLOB_SEPARATOR.len()..].trim().to_owned(),
+                })
+            }
+            None => Ok(Command {
+                files: None,
+                template: command.trim().to_owned(),
+            }),
+        }
+    }
+
+    fn applies_to(&self, name: &str) -> bool {
+        self.files
+            .as_ref()
+            .map(|f| f.is_match(name))
+            .unwrap_or(true)
+    }
+}
+
+/// Applies config changes by sending commands to a runtime API listening on a Unix domain socket, e.g. the HAProxy
+/// runtime API. The commands are templates that are expanded for each changed or deleted file they apply to. Each
+/// command is sent over a new connection and is expected to get an empty reply, as the HAProxy runtime API gives to
+/// the successful map and ACL commands.
+#[derive(Debug, Clone)]
+pub struct SocketBumper {
+    socket: PathBuf,
+    commands: Vec<Command>,
+    delete_commands: Vec<Command>,
+    ok_reply: Option<Regex>,
+    timeout: Duration,
+    rate_limiter: Option<RateLimiter>,
+    /// The changes suppressed by the rate limiter or failed to be sent, by the name of the file. `None` if the file
+    /// has been deleted.
+    deferred: BTreeMap<String, Option<ConfigFile>>,
+}
+
+impl SocketBumper {
+    /// Creates the bumper sending the commands for the changed files and the delete commands for the deleted ones.
+    pub fn new(socket: &Path, commands: &[String], delete_commands: &[String]) -> Result<Self> {
+        if commands.is_empty() && delete_commands.is_empty() {
+            return Err(Error::InitError(
+                "At least 1 socket command needs to be defined.".into(),
+            ));
+        }
+
+        Ok(SocketBumper {
+            socket: socket.to_owned(),
+            commands: parse(commands)?,
+            delete_commands: parse(delete_commands)?,
+            ok_reply: None,
+            timeout: Duration::from_secs(5),
+            rate_limiter: None,
+            deferred: BTreeMap::new(),
+        })
+    }
+
+    /// Accepts the non-empty replies matching the regex as a success, too.
+    pub fn with_ok_reply(mut self, ok_reply: Regex) -> Self {
+        self.ok_reply = Some(ok_reply);
+        self
+    }
+
+    /// Limits how often the commands can be sent. The changes suppressed by the limiter are merged into the next
+    /// allowed bump, see [bump_deferred](SocketBumper::bump_deferred).
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Sends the delete commands expanded for each of the deleted files and the commands expanded for each of the
+    /// changed files located in the base directory. Stops at the first failed command, the changes not sent yet are
+    /// remembered to be sent again by [bump_deferred](SocketBumper::bump_deferred), as the files have already been
+    /// written. If the rate limiter doesn't allow the bump, the changes are only remembered to be sent later. Use
+    /// [is_deferred](SocketBumper::is_deferred) to tell whether all the changes have been sent.
+    pub fn bump(
+        &mut self,
+        base_dir: &Path,
+        deleted: &[&String],
+        changed: &[(&String, &ConfigFile)],
+    ) -> Result<()> {
+        for name in deleted {
+            self.deferred.insert((*name).clone(), None);
+        }
+        for (name, cfg) in changed {
+            self.deferred.insert((*name).clone(), Some((*cfg).clone()));
+        }
+
+        if let Some(ref mut limiter) = self.rate_limiter {
+            if !limiter.acquire(Instant::now()) {
+                log::info!("Socket commands suppressed by the rate limit. They will be merged into the next allowed bump.");
+                return Ok(());
+            }
+        }
+
+        // the deletions go first
+        let (deletions, updates): (Vec<_>, Vec<_>) = std::mem::take(&mut self.deferred)
+            .into_iter()
+            .partition(|(_, cfg)| cfg.is_none());
+        let mut unsent = deletions.into_iter().chain(updates);
+
+        while let Some((name, cfg)) = unsent.next() {
+            let file = base_dir.join(&name);
+            let result = match cfg {
+                Some(ref cfg) => self.send_all(&self.commands, &name, &file, &cfg.content),
+                None => self.send_all(&self.delete_commands, &name, &file, ""),
+            };
+            if let Err(e) = result {
+                self.deferred.insert(name, cfg);
+                self.deferred.extend(unsent);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether changes have been suppressed by the rate limiter or failed to be sent, and not sent yet.
+    pub fn is_deferred(&self) -> bool {
+        !self.deferred.is_empty()
+    }
+
+    /// Whether the changes not sent yet are now allowed to be sent by the rate limiter.
+    pub fn is_deferred_due(&self) -> bool {
+        self.is_deferred()
+            && self
+                .rate_limiter
+                .as_ref()
+                .map(|l| l.ready(Instant::now()))
+                .unwrap_or(true)
+    }
+
+    /// Sends the changes not sent yet, if the rate limiter allows it by now. Returns true if they were sent.
+    pub fn bump_deferred(&mut self, base_dir: &Path) -> Result<bool> {
+        if !self.is_deferred_due() {
+            return Ok(false);
+        }
+
+        log::info!("Sending the socket commands not sent yet.");
+        self.bump(base_dir, &[], &[])?;
+        Ok(!self.is_deferred())
+    }
+
+    fn send_all(&self, commands: &[Command], name: &str, file: &Path, content: &str) -> Result<()> {
+        for command in commands.iter().filter(|c| c.applies_to(name)) {
+            // the lines of the file can contain the values of secrets, so they are never shown
+            let shown = expand_file(&command.template, name, file);
+            for command in expand(&command.template, name, file, content) {
+                self.send(&command, &shown)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the command, referring to it as `shown` in the logs and errors.
+    fn send(&self, command: &str, shown: &str) -> Result<String> {
+        log::debug!("Sending `{}` to socket {:?}", shown, self.socket);
+
+        let socket_error =
+            |e: &dyn ToString| Error::SocketError(format!("{:?}: {}", self.socket, e.to_string()));
+
+        let mut stream = UnixStream::connect(&self.socket).map_err(|e| socket_error(&e))?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .map_err(|e| socket_error(&e))?;
+        stream
+            .set_write_timeout(Some(self.timeout))
+            .map_err(|e| socket_error(&e))?;
+
+        stream
+            .write_all(format!("{}\n", command).as_bytes())
+            .map_err(|e| socket_error(&e))?;
+
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .map_err(|e| socket_error(&e))?;
+
+        let reply = response.trim();
+        log::debug!("Response to `{}`: `{}`", shown, reply);
+
+        let ok = reply.is_empty()
+            || self
+                .ok_reply
+                .as_ref()
+                .map(|r| r.is_match(reply))
+                .unwrap_or(false);
+        if ok {
+            Ok(response)
+        } else {
+            Err(Error::CommandError(shown.to_owned(), reply.to_owned()))
+        }
+    }
+}
+
+fn parse(commands: &[String]) -> Result<Vec<Command>> {
+    commands.iter().map(|c| Command::parse(c)).collect()
+}
+
+/// Expands the command template for a single changed file.
+fn expand(template: &str, name: &str, file: &Path, content: &str) -> Vec<String> {
+    let command = expand_file(template, name, file);
+
+    if command.contains(LINE_PLACEHOLDER) {
+        content
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| command.replace(LINE_PLACEHOLDER, l))
+            .collect()
+    } else {
+        vec![command]
+    }
+}
+
+/// Expands the placeholders of the file in the command template, leaving the one of the lines as it is.
+fn expand_file(template: &str, name: &str, file: &Path) -> String {
+    template
+        .replace(NAME_PLACEHOLDER, name)
+        .replace(FILE_PLACEHOLDER, &file.to_string_lossy())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_expansion() {
+        let file = Path::new("/etc/haproxy/hosts.map");
+        let content = "# comment\na.com be_a\n\nb.com be_b\n";
+
+        assert_eq!(
+            vec!["clear map /etc/haproxy/hosts.map"],
+            expand("clear map {file}", "hosts.map", file, content)
+        );
+        assert_eq!(
+            vec![
+                "add map /etc/haproxy/hosts.map a.com be_a",
+                "add map /etc/haproxy/hosts.map b.com be_b"
+            ],
+            expand("add map {file} {line}", "hosts.map", file, content)
+        );
+    }
+
+    /// Serves the socket, replying to each command by the reply function, and reports the received commands.
+    fn serve(socket: &Path, reply: fn(&str) -> &'static str) -> mpsc::Receiver<String> {
+        let listener = UnixListener::bind(socket).unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                let command = line.trim().to_string();
+                stream.write_all(reply(&command).as_bytes()).unwrap();
+                tx.send(command).unwrap();
+            }
+        });
+        rx
+    }
+
+    fn config_file(content: &str) -> ConfigFile {
+        ConfigFile {
+            content: content.into(),
+            digest: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_commands_sent_to_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("admin.sock");
+        let rx = serve(&socket, |_| "\n");
+
+        let mut bumper = SocketBumper::new(
+            &socket,
+            &["clear map {file}".into(), "add map {file} {line}".into()],
+            &[],
+        )
+        .unwrap();
+
+        let name = "hosts.map".to_string();
+        let cfg = config_file("a.com be_a\nb.com be_b");
+
+        bumper
+            .bump(Path::new("/maps"), &[], &[(&name, &cfg)])
+            .unwrap();
+
+        let received: Vec<String> = rx.iter().take(3).collect();
+        assert_eq!(
+            vec![
+                "clear map /maps/hosts.map",
+                "add map /maps/hosts.map a.com be_a",
+                "add map /maps/hosts.map b.com be_b"
+            ],
+            received
+        );
+    }
+
+    #[test]
+    fn test_commands_sent_for_matching_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("admin.sock");
+        let rx = serve(&socket, |_| "\n");
+
+        let mut bumper = SocketBumper::new(
+            &socket,
+            &[
+                "*.map => add map {file} {line}".into(),
+                "*.acl=>add acl {file} {line}".into(),
+            ],
+            &["*.map => clear map {file}".into()],
+        )
+        .unwrap();
+
+        let (map, acl, cfg, gone) = (
+            "hosts.map".to_string(),
+            "blocked.acl".to_string(),
+            "haproxy.cfg".to_string(),
+            "old.map".to_string(),
+        );
+        let (map_file, acl_file, cfg_file) = (
+            config_file("a.com be_a"),
+            config_file("10.0.0.1"),
+            config_file("global"),
+        );
+
+        bumper
+            .bump(
+                Path::new("/maps"),
+                &[&gone],
+                &[(&acl, &acl_file), (&cfg, &cfg_file), (&map, &map_file)],
+            )
+            .unwrap();
+
+        let received: Vec<String> = rx.iter().take(3).collect();
+        assert_eq!(
+            vec![
+                "clear map /maps/old.map",
+                "add acl /maps/blocked.acl 10.0.0.1",
+                "add map /maps/hosts.map a.com be_a",
+            ],
+            received
+        );
+    }
+
+    #[test]
+    fn test_commands_merged_when_rate_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("admin.sock");
+        let rx = serve(&socket, |_| "\n");
+
+        let mut bumper = SocketBumper::new(&socket, &["add map {file} {line}".into()], &[])
+            .unwrap()
+            .with_rate_limiter(RateLimiter::new(Duration::from_millis(200)));
+
+        let name = "hosts.map".to_string();
+        let (v1, v2, v3) = (
+            config_file("v1.com be"),
+            config_file("v2.com be"),
+            config_file("v3.com be"),
+        );
+
+        bumper
+            .bump(Path::new("/maps"), &[], &[(&name, &v1)])
+            .unwrap();
+        assert_eq!("add map /maps/hosts.map v1.com be", rx.recv().unwrap());
+
+        bumper
+            .bump(Path::new("/maps"), &[], &[(&name, &v2)])
+            .unwrap();
+        bumper
+            .bump(Path::new("/maps"), &[], &[(&name, &v3)])
+            .unwrap();
+        assert!(bumper.is_deferred());
+        assert!(!bumper.bump_deferred(Path::new("/maps")).unwrap());
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(bumper.bump_deferred(Path::new("/maps")).unwrap());
+        assert!(!bumper.is_deferred());
+        assert_eq!("add map /maps/hosts.map v3.com be", rx.recv().unwrap());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_unsent_commands_sent_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("admin.sock");
+
+        let mut bumper = SocketBumper::new(
+            &socket,
+            &["add map {file} {line}".into()],
+            &["clear map {file}".into()],
+        )
+        .unwrap();
+
+        let (gone, map) = ("old.map".to_string(), "hosts.map".to_string());
+        let cfg = config_file("a.com be_a");
+        assert!(bumper
+            .bump(Path::new("/maps"), &[&gone], &[(&map, &cfg)])
+            .is_err());
+        assert!(bumper.is_deferred());
+
+        let rx = serve(&socket, |_| "\n");
+        assert!(bumper.bump_deferred(Path::new("/maps")).unwrap());
+        assert!(!bumper.is_deferred());
+        assert_eq!(
+            vec![
+                "clear map /maps/old.map",
+                "add map /maps/hosts.map a.com be_a"
+            ],
+            rx.iter().take(2).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_error_reply_fails_the_bump() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("admin.sock");
+        let _rx = serve(&socket, |command| {
+            if command.starts_with("add map") {
+                "Unknown map identifier. Please use #<id> or <file>.\n"
+            } else {
+                "IP changed from '10.0.0.1' to '10.0.0.2'\n"
+            }
+        });
+
+        let name = "hosts.map".to_string();
+        let cfg = config_file("a.com be_a");
+        let mut bumper =
+            SocketBumper::new(&socket, &["add map {file} {line}".into()], &[]).unwrap();
+        match bumper.bump(Path::new("/maps"), &[], &[(&name, &cfg)]) {
+            Err(Error::CommandError(command, reply)) => {
+                assert_eq!("add map /maps/hosts.map {line}", command);
+                assert!(reply.starts_with("Unknown map identifier"));
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        let mut bumper =
+            SocketBumper::new(&socket, &["set server be/{name} addr 10.0.0.2".into()], &[])
+                .unwrap()
+                .with_ok_reply(Regex::new("^IP changed").unwrap());
+        bumper
+            .bump(Path::new("/maps"), &[], &[(&name, &cfg)])
+            .unwrap();
+    }
+}
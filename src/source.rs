@@ -0,0 +1,297 @@
+use super::operator::Error;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use kube::api::{Api, ListParams, Meta, WatchEvent};
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// A change of the watched objects.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event<Obj> {
+    Added(Obj),
+    Modified(Obj),
+    Deleted(Obj),
+    /// The complete list of the objects in a namespace, or in all namespaces if none is given. The known objects from
+    /// the namespace missing from the list have been deleted.
+    Resync(Option<String>, Vec<Obj>),
+}
+
+/// A source of the events driving the operator, e.g. a Kubernetes watch or a directory of manifests.
+pub trait EventSource<Obj> {
+    /// Waits for the next event. Returns `None` once the source is exhausted. The returned future may be dropped
+    /// before completion without losing any events.
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Event<Obj>, Error>>>;
+}
+
+/// A scripted sequence of events.
+impl<Obj: Send> EventSource<Obj> for VecDeque<Event<Obj>> {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Event<Obj>, Error>>> {
+        let ev = self.pop_front().map(Ok);
+        Box::pin(async move { ev })
+    }
+}
+
+/// The events sent by another task. The source is exhausted once all the senders are dropped.
+impl<Obj: Send> EventSource<Obj> for UnboundedReceiver<Result<Event<Obj>, Error>> {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Event<Obj>, Error>>> {
+        Box::pin(self.recv())
+    }
+}
+
+/// Watches the objects in a Kubernetes cluster. The objects are listed first and then watched from the version of the
+/// list. The watch is resumed from the last seen version when the server closes it and the objects are listed again,
+/// resulting in a resync, when the version is too old to resume from.
+pub struct KubeSource<Obj> {
+    received: UnboundedReceiver<Result<Event<Obj>, kube::Error>>,
+}
+
+impl<Obj> KubeSource<Obj>
+where
+    Obj: Clone + DeserializeOwned + Meta + Send + Sync + 'static,
+{
+    /// Starts watching the objects using all the provided APIs, e.g. one per namespace. Each API is paired with the
+    /// namespace it is restricted to, if any.
+    pub fn new(apis: Vec<(Option<String>, Api<Obj>)>, params: ListParams) -> Self {
+        let (events, received) = mpsc::unbounded_channel();
+        for (namespace, api) in apis {
+            tokio::spawn(watch(namespace, api, params.clone(), events.clone()));
+        }
+
+        KubeSource { received }
+    }
+}
+
+impl<Obj: Send> EventSource<Obj> for KubeSource<Obj> {
+    fn next(&mut self) -> BoxFuture<'_, Option<Result<Event<Obj>, Error>>> {
+        Box::pin(async move {
+            self.received
+                .recv()
+                .await
+                .map(|ev| ev.map_err(|e| e.into()))
+        })
+    }
+}
+
+/// The delay before the first retry of a failed list or watch.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The longest delay between the retries of a failing list or watch.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// The delays between the retries of a failing operation, doubling with every failure up to the maximum. A success
+/// resets the delay.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// Returns the delay before the next retry.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = std::cmp::min(self.next * 2, self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+
+    /// Waits before the next retry.
+    pub async fn wait(&mut self) {
+        tokio::time::delay_for(self.next_delay()).await;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(INITIAL_RETRY_DELAY, MAX_RETRY_DELAY)
+    }
+}
+
+/// Whether the request may succeed when retried, e.g. after the API server was unreachable or overloaded. Errors
+/// like a missing permission are not expected to go away by themselves, and neither is an object that can't be
+/// decoded when the same version is requested again.
+pub fn is_transient(e: &kube::Error) -> bool {
+    match e {
+        kube::Error::Api(e) => e.code == 429 || e.code >= 500,
+        kube::Error::ReqwestError(_)
+        | kube::Error::HttpError(_)
+        | kube::Error::RequestSend
+        | kube::Error::RequestParse => true,
+        _ => false,
+    }
+}
+
+/// Whether the watched version is too old to resume the watch from.
+fn is_gone(e: &kube::Error) -> bool {
+    match e {
+        kube::Error::Api(e) => e.code == 410,
+        _ => false,
+    }
+}
+
+/// Sends the events about the objects to the channel until the first error that is not transient.
+async fn watch<Obj>(
+    namespace: Option<String>,
+    api: Api<Obj>,
+    params: ListParams,
+    events: UnboundedSender<Result<Event<Obj>, kube::Error>>,
+) where
+    Obj: Clone + DeserializeOwned + Meta + Send + Sync,
+{
+    if let Err(e) = list_and_watch(namespace, &api, &params, &events).await {
+        let _ = events.send(Err(e));
+    }
+}
+
+/// Returns `Ok` only once the receiving end of the channel is closed. The transient errors are retried with a backoff,
+/// which also applies to the watches closed before receiving any event, so that a misbehaving server isn't flooded.
+async fn list_and_watch<Obj>(
+    namespace: Option<String>,
+    api: &Api<Obj>,
+    params: &ListParams,
+    events: &UnboundedSender<Result<Event<Obj>, kube::Error>>,
+) -> Result<(), kube::Error>
+where
+    Obj: Clone + DeserializeOwned + Meta + Send + Sync,
+{
+    let mut backoff = Backoff::default();
+
+    loop {
+        let list = match api.list(params).await {
+            Ok(list) => list,
+            Err(e) if is_transient(&e) => {
+                log::warn!("Failed to list objects, retrying: {}", e);
+                backoff.wait().await;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let mut version = list.metadata.resource_version.unwrap_or_else(|| "0".into());
+        if events
+            .send(Ok(Event::Resync(namespace.clone(), list.items)))
+            .is_err()
+        {
+            return Ok(());
+        }
+
+        'watch: loop {
+            let mut stream = match api.watch(params, &version).await {
+                Ok(stream) => stream.boxed(),
+                Err(ref e) if is_gone(e) => break 'watch,
+                Err(e) if is_transient(&e) => {
+                    log::warn!("Failed to watch objects, retrying: {}", e);
+                    backoff.wait().await;
+                    continue 'watch;
+                }
+                Err(e) => return Err(e),
+            };
+            let mut received = false;
+            while let Some(ev) = stream.next().await {
+                let ev = match ev {
+                    Ok(ev) => ev,
+                    Err(e) if is_transient(&e) => {
+                        log::warn!("Failed to watch objects, resuming: {}", e);
+                        backoff.wait().await;
+                        continue 'watch;
+                    }
+                    // resuming would decode the same event again, the list skips past it
+                    Err(kube::Error::SerdeError(e)) => {
+                        log::warn!(
+                            "Failed to decode a watch event, listing the objects again: {}",
+                            e
+                        );
+                        backoff.wait().await;
+                        break 'watch;
+                    }
+                    Err(e) => return Err(e),
+                };
+                received = true;
+                backoff.reset();
+
+                let ev = match ev {
+                    WatchEvent::Added(o) => Event::Added(o),
+                    WatchEvent::Modified(o) => Event::Modified(o),
+                    WatchEvent::Deleted(o) => Event::Deleted(o),
+                    WatchEvent::Bookmark(o) => {
+                        log::trace!("Received bookmark.");
+                        version = o.resource_ver().unwrap_or(version);
+                        continue;
+                    }
+                    WatchEvent::Error(e) if e.code == 410 => {
+                        log::debug!(
+                            "The watched version is too old, listing the objects again: {}",
+                            e
+                        );
+                        break 'watch;
+                    }
+                    WatchEvent::Error(e) => {
+                        log::error!("Failed to watch objects: {}", e);
+                        continue;
+                    }
+                };
+
+                if let Event::Added(ref o) | Event::Modified(ref o) | Event::Deleted(ref o) = ev {
+                    version = o.resource_ver().unwrap_or(version);
+                }
+                if events.send(Ok(ev)).is_err() {
+                    return Ok(());
+                }
+            }
+            log::trace!("The watch was closed, resuming from version {}.", version);
+            if !received {
+                backoff.wait().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(vec![1, 2, 4, 5, 5], delays);
+
+        backoff.reset();
+        assert_eq!(Duration::from_secs(1), backoff.next_delay());
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        let api_error = |code| {
+            kube::Error::Api(kube::error::ErrorResponse {
+                status: "Failure".into(),
+                message: String::new(),
+                reason: String::new(),
+                code,
+            })
+        };
+
+        assert!(is_transient(&api_error(503)));
+        assert!(is_transient(&api_error(429)));
+        assert!(!is_transient(&api_error(403)));
+        assert!(!is_transient(&api_error(410)));
+        assert!(is_gone(&api_error(410)));
+        assert!(is_transient(&kube::Error::RequestSend));
+        let undecodable = serde_json::from_str::<u32>("{").unwrap_err();
+        assert!(!is_transient(&kube::Error::SerdeError(undecodable)));
+        assert!(!is_transient(&kube::Error::RequestValidation("no".into())));
+    }
+}
@@ -0,0 +1,402 @@
+use super::secrets::SecretStore;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// The annotation on a config map enabling the rendering of its values as templates, if set to `true`.
+pub const TEMPLATE_ANNOTATION: &str = "cm-bump/template";
+
+/// The annotation on a config map enabling the expansion of the environment variables in its values, if set to
+/// `true`. See [expand_env](expand_env).
+pub const EXPAND_ENV_ANNOTATION: &str = "cm-bump/expand-env";
+
+#[derive(Debug, Clone, Error)]
+pub enum Error {
+    #[error("Unterminated placeholder starting with `{0}`")]
+    Unterminated(String),
+    #[error("Invalid placeholder `{0}`, expected `env.NAME`, `pod.NAME`, `key.NAME` or `secret.NAME.KEY`")]
+    InvalidPlaceholder(String),
+    #[error("Undefined variable `{0}`")]
+    Undefined(String),
+    #[error("Invalid variable reference `{0}`, expected `${{NAME}}` or `${{NAME:-default}}`")]
+    InvalidReference(String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Renders the values of config maps. The placeholders in the form of `{{ source.NAME }}` are replaced by:
+///
+/// * `env.NAME` - the environment variable `NAME` of cm-bump,
+/// * `pod.NAME` - the contents of the file `NAME` in the directory with the Downward API files of the pod, without the
+///   trailing newline,
+/// * `key.NAME` - the unrendered value of the key `NAME` of the same config map,
+/// * `secret.NAME.KEY` - the value of the key `KEY` of the secret `NAME` in the namespace of the config map.
+///
+/// A placeholder that can't be resolved fails the rendering.
+#[derive(Debug, Clone, Default)]
+pub struct Renderer {
+    downward_api_dir: Option<PathBuf>,
+    secrets: Option<SecretStore>,
+}
+
+impl Renderer {
+    /// Sets the directory the Downward API volume of the pod is mounted at.
+    pub fn with_downward_api_dir(mut self, dir: &Path) -> Self {
+        self.downward_api_dir = Some(dir.to_owned());
+        self
+    }
+
+    /// Sets the store of the secrets the templates can refer to.
+    pub fn with_secrets(mut self, secrets: SecretStore) -> Self {
+        self.secrets = Some(secrets);
+        self
+    }
+
+    /// Checks whether the annotations of a config map enable the rendering.
+    pub fn is_enabled(annotations: &BTreeMap<String, String>) -> bool {
+        is_true(annotations, TEMPLATE_ANNOTATION)
+    }
+
+    /// Renders the template using the namespace and the provided keys of the config map.
+    pub fn render(
+        &self,
+        template: &str,
+        namespace: Option<&str>,
+        keys: &BTreeMap<String, String>,
+    ) -> Result<String> {
+        self.render_with(template, namespace, keys, |text| Ok(text.to_owned()))
+    }
+
+    /// Renders the template like [render](Renderer::render), passing the text around the placeholders through the
+    /// provided function, e.g. [expand_env](expand_env). The values of the placeholders are left as they are.
+    pub fn render_with(
+        &self,
+        template: &str,
+        namespace: Option<&str>,
+        keys: &BTreeMap<String, String>,
+        text: impl Fn(&str) -> Result<String>,
+    ) -> Result<String> {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find("{{") {
+            rendered.push_str(&text(&rest[..start])?);
+            let placeholder = &rest[start..];
+            let end = match placeholder.find("}}") {
+                Some(end) => end,
+                None => {
+                    let excerpt: String = placeholder.chars().take(20).collect();
+                    return Err(Error::Unterminated(excerpt));
+                }
+            };
+
+            rendered.push_str(&self.resolve(placeholder[2..end].trim(), namespace, keys)?);
+            rest = &placeholder[end + 2..];
+        }
+        rendered.push_str(&text(rest)?);
+
+        Ok(rendered)
+    }
+
+    fn resolve(
+        &self,
+        placeholder: &str,
+        namespace: Option<&str>,
+        keys: &BTreeMap<String, String>,
+    ) -> Result<String> {
+        let undefined = || Error::Undefined(placeholder.to_owned());
+
+        let mut parts = placeholder.splitn(2, '.');
+        match (parts.next(), parts.next()) {
+            (Some("env"), Some(name)) if !name.is_empty() => {
+                std::env::var(name).map_err(|_| undefined())
+            }
+            (Some("pod"), Some(name)) if is_file_name(name) => {
+                let dir = self.downward_api_dir.as_ref().ok_or_else(undefined)?;
+                std::fs::read_to_string(dir.join(name))
+                    .map(|v| v.trim_end_matches('\n').to_owned())
+                    .map_err(|_| undefined())
+            }
+            (Some("key"), Some(name)) if !name.is_empty() => {
+                keys.get(name).cloned().ok_or_else(undefined)
+            }
+            (Some("secret"), Some(reference)) => match secret_reference(reference) {
+                Some((name, key)) => match (&self.secrets, namespace) {
+                    (Some(secrets), Some(namespace)) => {
+                        secrets.get(namespace, name, key).ok_or_else(undefined)
+                    }
+                    _ => Err(undefined()),
+                },
+                None => Err(Error::InvalidPlaceholder(placeholder.to_owned())),
+            },
+            _ => Err(Error::InvalidPlaceholder(placeholder.to_owned())),
+        }
+    }
+}
+
+/// Finds the names of the secrets the template refers to.
+pub fn secret_references(template: &str) -> BTreeSet<String> {
+    references(template, "secret")
+        .filter_map(secret_reference)
+        .map(|(name, _)| name.to_owned())
+        .collect()
+}
+
+/// Finds the names of the Downward API files the template refers to.
+pub fn pod_references(template: &str) -> BTreeSet<String> {
+    references(template, "pod")
+        .filter(|name| is_file_name(name))
+        .map(|name| name.to_owned())
+        .collect()
+}
+
+/// The references of the placeholders from the source, e.g. `NAME.KEY` of `{{ secret.NAME.KEY }}`.
+fn references<'a>(template: &'a str, source: &'a str) -> impl Iterator<Item = &'a str> {
+    let mut rest = template;
+
+    std::iter::from_fn(move || {
+        while let Some(start) = rest.find("{{") {
+            let placeholder = &rest[start + 2..];
+            let end = placeholder.find("}}")?;
+            rest = &placeholder[end + 2..];
+
+            let mut parts = placeholder[..end].trim().splitn(2, '.');
+            if let (Some(s), Some(reference)) = (parts.next(), parts.next()) {
+                if s == source {
+                    return Some(reference);
+                }
+            }
+        }
+        None
+    })
+}
+
+/// Splits the `NAME.KEY` reference to a key of a secret.
+fn secret_reference(reference: &str) -> Option<(&str, &str)> {
+    let mut parts = reference.splitn(2, '.');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(key)) if !name.is_empty() && !key.is_empty() => Some((name, key)),
+        _ => None,
+    }
+}
+
+/// Checks whether the annotations of a config map enable the expansion of the environment variables.
+pub fn is_expand_env_enabled(annotations: &BTreeMap<String, String>) -> bool {
+    is_true(annotations, EXPAND_ENV_ANNOTATION)
+}
+
+fn is_true(annotations: &BTreeMap<String, String>, annotation: &str) -> bool {
+    annotations
+        .get(annotation)
+        .map(|v| v.trim().eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Expands the references to the environment variables of cm-bump in the value. `${NAME}` is replaced by the value
+/// of the variable and fails if it is not set, `${NAME:-default}` falls back to the default if the variable is not
+/// set or is empty. `$$` right before `{` stands for a literal `$`, e.g. `$${` for `${` and `$$${NAME}` for `$` followed
+/// by the value, other uses of `$` are left as they are.
+pub fn expand_env(value: &str) -> Result<String> {
+    expand(value, |name| std::env::var(name).ok())
+}
+
+fn expand(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        // the dollars before the `{` are paired up into literal ones, an unpaired one starts the reference
+        let dollars = rest[..=start].len() - rest[..=start].trim_end_matches('$').len();
+        expanded.push_str(&rest[..start + 1 - dollars]);
+        expanded.push_str(&"$".repeat(dollars / 2));
+        let unpaired = dollars % 2 == 1;
+        if !unpaired {
+            expanded.push('{');
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        let reference = &rest[start..];
+        let end = match reference.find('}') {
+            Some(end) => end,
+            None => {
+                let excerpt: String = reference.chars().take(20).collect();
+                return Err(Error::Unterminated(excerpt));
+            }
+        };
+
+        let inner = &reference[2..end];
+        let (name, default) = match inner.find(":-") {
+            Some(idx) => (&inner[..idx], Some(&inner[idx + 2..])),
+            None => (inner, None),
+        };
+        if !is_variable_name(name) {
+            return Err(Error::InvalidReference(reference[..=end].to_owned()));
+        }
+
+        match (lookup(name), default) {
+            (Some(v), Some(default)) if v.is_empty() => expanded.push_str(default),
+            (Some(v), _) => expanded.push_str(&v),
+            (None, Some(default)) => expanded.push_str(default),
+            (None, None) => return Err(Error::Undefined(name.to_owned())),
+        }
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Checks that the name doesn't escape the Downward API directory.
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && name != ".."
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        std::env::set_var("CM_BUMP_TEST_RESOLVER", "10.0.0.10");
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("namespace"), "app\n").unwrap();
+
+        let renderer = Renderer::default().with_downward_api_dir(dir.path());
+        let mut keys = BTreeMap::new();
+        keys.insert("ttl".to_string(), "1m".to_string());
+
+        assert_eq!(
+            "resolver 10.0.0.10 valid=1m; # app",
+            renderer
+                .render(
+                    "resolver {{ env.CM_BUMP_TEST_RESOLVER }} valid={{key.ttl}}; # {{ pod.namespace }}",
+                    None,
+                    &keys
+                )
+                .unwrap()
+        );
+        assert_eq!(
+            "no placeholders",
+            renderer.render("no placeholders", None, &keys).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_strict_failures() {
+        let renderer = Renderer::default();
+        let keys = BTreeMap::new();
+
+        assert!(matches!(
+            renderer.render("{{ env.CM_BUMP_TEST_UNDEFINED }}", None, &keys),
+            Err(Error::Undefined(_))
+        ));
+        assert!(matches!(
+            renderer.render("{{ key.ttl }}", None, &keys),
+            Err(Error::Undefined(_))
+        ));
+        // no Downward API directory configured
+        assert!(matches!(
+            renderer.render("{{ pod.namespace }}", None, &keys),
+            Err(Error::Undefined(_))
+        ));
+        assert!(matches!(
+            renderer.render("{{ pod.../etc/passwd }}", None, &keys),
+            Err(Error::InvalidPlaceholder(_))
+        ));
+        assert!(matches!(
+            renderer.render("{{ RESOLVER }}", None, &keys),
+            Err(Error::InvalidPlaceholder(_))
+        ));
+        assert!(matches!(
+            renderer.render("resolver {{ env.RESOLVER", None, &keys),
+            Err(Error::Unterminated(_))
+        ));
+    }
+
+    #[test]
+    fn test_secrets() {
+        let secrets = SecretStore::default();
+        let mut data = BTreeMap::new();
+        data.insert("password".to_string(), "s3cr3t".to_string());
+        secrets.update("app", "db", Some(data));
+
+        let renderer = Renderer::default().with_secrets(secrets);
+        let keys = BTreeMap::new();
+        let template = "user app\npassword {{ secret.db.password }}\n";
+
+        assert_eq!(
+            "user app\npassword s3cr3t\n",
+            renderer.render(template, Some("app"), &keys).unwrap()
+        );
+        // only the secrets from the namespace of the config map are available
+        assert!(matches!(
+            renderer.render(template, Some("other"), &keys),
+            Err(Error::Undefined(_))
+        ));
+        assert!(matches!(
+            renderer.render("{{ secret.db.user }}", Some("app"), &keys),
+            Err(Error::Undefined(_))
+        ));
+        assert!(matches!(
+            renderer.render("{{ secret.db }}", Some("app"), &keys),
+            Err(Error::InvalidPlaceholder(_))
+        ));
+
+        assert_eq!(
+            vec!["db", "tls"],
+            secret_references(
+                "{{ secret.db.password }} {{secret.tls.key}} {{ secret.db.user }} {{ env.HOME }}"
+            )
+            .into_iter()
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let lookup = |name: &str| match name {
+            "POD_IP" => Some("10.1.2.3".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+
+        assert_eq!(
+            "listen 10.1.2.3:8080; ttl=1m; $host ${literal}",
+            expand(
+                "listen ${POD_IP}:8080; ttl=${DNS_TTL:-1m}; $host $${literal}",
+                lookup
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            "${POD_IP} $10.1.2.3 $${POD_IP}",
+            expand("$${POD_IP} $$${POD_IP} $$$${POD_IP}", lookup).unwrap()
+        );
+        assert_eq!("default", expand("${EMPTY:-default}", lookup).unwrap());
+        assert_eq!("", expand("${EMPTY}${UNSET:-}", lookup).unwrap());
+
+        assert!(matches!(
+            expand("${UNSET}", lookup),
+            Err(Error::Undefined(_))
+        ));
+        assert!(matches!(
+            expand("${1}", lookup),
+            Err(Error::InvalidReference(_))
+        ));
+        assert!(matches!(
+            expand("${POD_IP", lookup),
+            Err(Error::Unterminated(_))
+        ));
+    }
+}
@@ -1,21 +1,93 @@
 use super::bumper::Bumper;
+use super::events::{EventRecorder, EventType};
+use super::health::HealthChecker;
+use super::keys::KeyFilter;
+use super::metrics::Metrics;
 use super::operator;
+use super::reload::{ReloadVerifier, Snapshot, Verification};
+use super::socket::SocketBumper;
+use super::template::{self, Renderer};
+use super::validator::Validator;
 use k8s_openapi::api::core::v1::ConfigMap;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Debug, Clone)]
 pub struct ConfigUpdater {
     dir: String,
     bumper: Option<Bumper>,
+    socket_bumper: Option<SocketBumper>,
+    validator: Option<Validator>,
+    health_checker: Option<HealthChecker>,
+    reload_verifier: Option<ReloadVerifier>,
+    /// The state of the process observed before the signal sequence that is still running, to verify the reload
+    /// against once the sequence is done.
+    reload_before: Option<Snapshot>,
+    /// The verification of the reload after the last bump, until it passes or times out.
+    reload: Option<Verification>,
+    /// The filter of the keys to persist applied to all config maps.
+    key_filter: KeyFilter,
+    /// Renders the values of the config maps that enable it.
+    renderer: Renderer,
+    /// The config maps applied but not confirmed to be good yet, waiting for the deferred bump or for their health
+    /// check.
+    pending: BTreeMap<String, PendingCheck>,
+    /// The last revision of the files of each config map that passed the health check.
+    last_good: HashMap<String, ConfigFiles>,
+    /// The config map each file in the directory comes from.
+    owners: HashMap<String, String>,
+    /// The digests of the files of each config map rolled back after failing the health check. The revision is not
+    /// applied again, e.g. on a resync, until the config map changes.
+    rejected: HashMap<String, BTreeMap<String, String>>,
+    events: EventRecorder,
+    metrics: Arc<Metrics>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct ConfigFile {
     pub content: String,
     pub digest: String,
 }
 
-type ConfigFiles = BTreeMap<String, ConfigFile>;
+/// The content is left out because it can contain the values of secrets.
+impl std::fmt::Debug for ConfigFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConfigFile")
+            .field("digest", &self.digest)
+            .finish()
+    }
+}
+
+pub type ConfigFiles = BTreeMap<String, ConfigFile>;
+
+/// The files of a config map applied by a bump whose health is yet to be checked.
+#[derive(Debug, Clone)]
+struct PendingCheck {
+    /// The applied files, or `None` if the config map has been deleted.
+    applied: Option<ConfigFiles>,
+    /// When the health is to be checked, or `None` while the bump is deferred.
+    due: Option<Instant>,
+    /// Whether the files are the last known good revision restored by a rollback, which isn't rolled back again.
+    rollback: bool,
+}
+
+/// The files prepared from a single config map.
+#[derive(Debug, Clone)]
+pub struct ConfigMapFiles {
+    pub name: String,
+    pub files: ConfigFiles,
+    /// The reason the files couldn't be prepared. The files then have their unrendered contents and are not applied.
+    pub error: Option<PrepareError>,
+}
+
+/// Why the files of a config map couldn't be prepared.
+#[derive(Debug, Clone)]
+pub struct PrepareError {
+    /// The reason of the Event reporting the failure.
+    pub reason: &'static str,
+    pub message: String,
+}
 
 impl ConfigUpdater {
     pub fn new(base_dir: &str, bumper: Option<Bumper>) -> Result<Self, operator::Error> {
@@ -37,7 +109,21 @@ impl ConfigUpdater {
             match base_dir.to_str() {
                 Some(p) => Ok(ConfigUpdater {
                     dir: p.to_owned(),
-                    bumper: bumper,
+                    bumper,
+                    socket_bumper: None,
+                    validator: None,
+                    health_checker: None,
+                    reload_verifier: None,
+                    reload_before: None,
+                    reload: None,
+                    key_filter: KeyFilter::default(),
+                    renderer: Renderer::default(),
+                    pending: BTreeMap::new(),
+                    last_good: HashMap::new(),
+                    owners: HashMap::new(),
+                    rejected: HashMap::new(),
+                    events: EventRecorder::disabled(),
+                    metrics: Arc::new(Metrics::default()),
                 }),
                 None => Err(operator::Error::OperatorError(format!(
                     "Base dir path `{}` is not valid UTF-8.",
@@ -47,30 +133,638 @@ impl ConfigUpdater {
         }
     }
 
+    /// Sets the runtime API to send commands to for each changed file. The commands are sent before the process is
+    /// signalled.
+    pub fn with_socket_bumper(mut self, socket_bumper: SocketBumper) -> Self {
+        self.socket_bumper = Some(socket_bumper);
+        self
+    }
+
+    /// Sets the validator to run against the staged config changes before they are applied.
+    pub fn with_validator(mut self, validator: Validator) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Sets the health checker to run after each bump. If the check fails, the last known good revision of the
+    /// changed config map is restored and the process bumped again.
+    pub fn with_health_checker(mut self, health_checker: HealthChecker) -> Self {
+        self.health_checker = Some(health_checker);
+        self
+    }
+
+    /// Sets the verifier checking that the process actually reloaded its configuration after each bump. The outcome
+    /// is only reported, it doesn't cause a rollback.
+    pub fn with_reload_verifier(mut self, reload_verifier: ReloadVerifier) -> Self {
+        self.reload_verifier = Some(reload_verifier);
+        self
+    }
+
+    /// Sets the filter of the keys to persist from all config maps. Config maps can further restrict the keys using
+    /// the [INCLUDE_ANNOTATION](super::keys::INCLUDE_ANNOTATION) and
+    /// [EXCLUDE_ANNOTATION](super::keys::EXCLUDE_ANNOTATION) annotations.
+    pub fn with_key_filter(mut self, key_filter: KeyFilter) -> Self {
+        self.key_filter = key_filter;
+        self
+    }
+
+    /// Sets the renderer of the config maps enabling the rendering using the
+    /// [TEMPLATE_ANNOTATION](super::template::TEMPLATE_ANNOTATION) annotation.
+    pub fn with_renderer(mut self, renderer: Renderer) -> Self {
+        self.renderer = renderer;
+        self
+    }
+
+    /// Sets the recorder to report notable occurrences like rollbacks with.
+    pub fn with_event_recorder(mut self, events: EventRecorder) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Sets the metrics to count the outcomes of the bumps in.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
     fn to_path(&self, file: &str) -> Box<std::path::Path> {
         let mut path = std::path::PathBuf::from(&self.dir);
         path.push(file);
         path.into_boxed_path()
     }
+
+    /// Expands the environment variables in the value and renders it as a template, as enabled. Neither the values of
+    /// the variables are rendered, nor are the variables expanded in the values of the placeholders.
+    fn render(
+        &self,
+        value: &str,
+        namespace: Option<&str>,
+        keys: &BTreeMap<String, String>,
+        expand_env: bool,
+        render: bool,
+    ) -> Result<String, template::Error> {
+        match (expand_env, render) {
+            (true, true) => self
+                .renderer
+                .render_with(value, namespace, keys, template::expand_env),
+            (true, false) => template::expand_env(value),
+            (false, true) => self.renderer.render(value, namespace, keys),
+            (false, false) => Ok(value.to_owned()),
+        }
+    }
+
+    /// Checks whether the file on disk differs from the provided config file.
+    fn needs_update(&self, name: &str, cfg: &ConfigFile) -> bool {
+        let path = self.to_path(name);
+        if !path.exists() {
+            return true;
+        }
+
+        match std::fs::read(path) {
+            Ok(data) => {
+                let mut sha = sha1::Sha1::new();
+                sha.update(&data);
+                if sha.digest().to_string() == cfg.digest {
+                    log::debug!("Config file `{}` hasn't changed. Skipping update.", name);
+                    false
+                } else {
+                    true
+                }
+            }
+            Err(e) => {
+                log::warn!("Will overwrite the config file `{}` forcefully because of failure to compute its checksum: {}", name, e);
+                true
+            }
+        }
+    }
+
+    /// Finds the files from old that are no longer in new and the files from new that differ from what is on disk.
+    fn diff<'a>(
+        &self,
+        old: Option<&'a ConfigFiles>,
+        new: Option<&'a ConfigFiles>,
+    ) -> (Vec<&'a String>, Vec<(&'a String, &'a ConfigFile)>) {
+        let deleted = old
+            .map(|old_files| {
+                old_files
+                    .keys()
+                    .filter(|f| !new.map(|n| n.contains_key(*f)).unwrap_or(false))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let changed = new
+            .map(|new_files| {
+                new_files
+                    .iter()
+                    .filter(|(name, cfg)| self.needs_update(name, cfg))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        (deleted, changed)
+    }
+
+    /// Makes sure that none of the files comes from another config map, e.g. the same key of config maps in different
+    /// namespaces, as the config maps would keep overwriting each other's files.
+    fn check_conflicts(&self, name: &str, files: &ConfigFiles) -> Result<(), operator::Error> {
+        let conflicts: Vec<String> = files
+            .keys()
+            .filter_map(|f| match self.owners.get(f) {
+                Some(owner) if owner != name => {
+                    Some(format!("`{}` comes from config map `{}`", f, owner))
+                }
+                _ => None,
+            })
+            .collect();
+
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+
+        let msg = format!(
+            "Refusing to apply config map `{}` with files that already come from other config maps: {}.",
+            name,
+            conflicts.join(", ")
+        );
+        log::error!("{}", msg);
+        self.events.record(EventType::Warning, "FileConflict", &msg);
+        Err(operator::Error::OperatorError(msg))
+    }
+
+    /// Records the config map as the one the new files come from, instead of the old ones.
+    fn own(&mut self, name: &str, old: Option<&ConfigFiles>, new: Option<&ConfigFiles>) {
+        for f in old.iter().flat_map(|files| files.keys()) {
+            if self.owners.get(f).map(|o| o == name).unwrap_or(false) {
+                self.owners.remove(f);
+            }
+        }
+        for f in new.iter().flat_map(|files| files.keys()) {
+            self.owners.insert(f.clone(), name.to_owned());
+        }
+    }
+
+    /// Deletes and writes the files. Returns true if any file on disk has been modified.
+    fn apply(&self, deleted: &[&String], changed: &[(&String, &ConfigFile)]) -> bool {
+        let mut updated = false;
+
+        for f in deleted {
+            let path = self.to_path(f);
+            log::debug!("Deleting config file {:?}", path);
+            match std::fs::remove_file(path) {
+                Err(e) => {
+                    log::error!(
+                        "Failed to delete a no longer required config file `{}`: {}",
+                        f,
+                        e
+                    );
+                }
+                _ => {
+                    updated = true;
+                }
+            }
+        }
+
+        for (name, cfg) in changed {
+            match std::fs::write(self.to_path(name), cfg.content.as_bytes()) {
+                Ok(_) => {
+                    log::debug!("Updated the config file `{}`", name);
+                    updated = true;
+                }
+                Err(e) => {
+                    log::error!("Failed to update the config file `{}`: {}", name, e);
+                }
+            }
+        }
+
+        updated
+    }
+
+    /// Sends the changes to the socket and bumps the process. Returns false if the bump of the process has been
+    /// deferred, by the rate limit or to wait for the process to appear.
+    fn bump(
+        &mut self,
+        deleted: &[&String],
+        changed: &[(&String, &ConfigFile)],
+    ) -> Result<bool, operator::Error> {
+        let snapshot = self.snapshot_before_bump();
+
+        // a failed bump is deferred, so that it is retried from the tick
+        let socket = match self.socket_bumper {
+            Some(ref mut s) => {
+                log::debug!("Sending the changes to the configured socket.");
+                s.bump(std::path::Path::new(&self.dir), deleted, changed)
+                    .map_err(|e| format!("{}", e))
+            }
+            None => Ok(()),
+        };
+
+        let process = match self.bumper {
+            Some(ref mut b) => {
+                log::debug!("Bumping the configured process.");
+                b.bump().map_err(|e| {
+                    b.defer();
+                    format!("{}", e)
+                })
+            }
+            None => Ok(()),
+        };
+
+        if self.is_bump_deferred() {
+            self.keep_snapshot(snapshot);
+            socket
+                .and(process)
+                .map_err(operator::Error::OperatorError)?;
+            return Ok(false);
+        }
+
+        self.start_reload_verification(snapshot);
+
+        Ok(true)
+    }
+
+    /// Bumps after the files of the config map have been applied and schedules the health check. The files are
+    /// already on disk, so a failed bump doesn't fail the config map, it is retried from the tick instead.
+    fn bump_applied(
+        &mut self,
+        name: &str,
+        applied: Option<&ConfigFiles>,
+        rollback: bool,
+        deleted: &[&String],
+        changed: &[(&String, &ConfigFile)],
+    ) {
+        let bumped = match self.bump(deleted, changed) {
+            Ok(bumped) => bumped,
+            Err(e) => {
+                let msg = format!(
+                    "Failed to bump after applying config map `{}`, retrying: {}",
+                    name, e
+                );
+                log::error!("{}", msg);
+                self.events.record(EventType::Warning, "BumpFailed", &msg);
+                false
+            }
+        };
+        self.schedule_health_check(name, applied, rollback, bumped);
+    }
+
+    /// Does the deferred bump of the socket and the process if it is due and schedules the health checks of the
+    /// config maps applied in the meantime.
+    fn bump_deferred(&mut self) -> Result<(), operator::Error> {
+        let socket_due = self
+            .socket_bumper
+            .as_ref()
+            .map(|s| s.is_deferred_due())
+            .unwrap_or(false);
+        let process_due = self
+            .bumper
+            .as_mut()
+            .map(|b| b.is_deferred_due())
+            .unwrap_or(false);
+        if !socket_due && !process_due {
+            return Ok(());
+        }
+
+        let snapshot = self.snapshot_before_bump();
+
+        if let Some(ref mut s) = self.socket_bumper {
+            s.bump_deferred(std::path::Path::new(&self.dir))
+                .map_err(|e| operator::Error::OperatorError(format!("{}", e)))?;
+        }
+
+        if process_due {
+            if let Some(ref mut b) = self.bumper {
+                b.bump_deferred().map_err(|e| {
+                    b.defer();
+                    operator::Error::OperatorError(format!("{}", e))
+                })?;
+            }
+        }
+
+        if self.is_bump_deferred() {
+            self.keep_snapshot(snapshot);
+            return Ok(());
+        }
+
+        self.start_reload_verification(snapshot);
+
+        let waiting: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, check)| check.due.is_none())
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in waiting {
+            if let Some(check) = self.pending.remove(&name) {
+                self.schedule_health_check(&name, check.applied.as_ref(), check.rollback, true);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Observes the process before a bump for the reload verification. While the signal sequence of an earlier bump is
+    /// still running, the observation from before that sequence is used instead.
+    fn snapshot_before_bump(&mut self) -> Option<Snapshot> {
+        if self.is_signalling() {
+            self.reload_before.take()
+        } else {
+            self.reload_snapshot()
+        }
+    }
+
+    /// Keeps the observation from before the bump if its signal sequence is still running.
+    fn keep_snapshot(&mut self, snapshot: Option<Snapshot>) {
+        if self.is_signalling() {
+            self.reload_before = snapshot;
+        }
+    }
+
+    /// Whether the bump of the socket or the process has been deferred and is not done yet.
+    fn is_bump_deferred(&self) -> bool {
+        self.socket_bumper
+            .as_ref()
+            .map(|s| s.is_deferred())
+            .unwrap_or(false)
+            || self
+                .bumper
+                .as_ref()
+                .map(|b| b.is_deferred())
+                .unwrap_or(false)
+    }
+
+    fn is_signalling(&self) -> bool {
+        self.bumper
+            .as_ref()
+            .map(|b| b.is_signalling())
+            .unwrap_or(false)
+    }
+
+    fn reload_snapshot(&mut self) -> Option<Snapshot> {
+        match self.reload_verifier {
+            Some(ref v) => Some(v.snapshot(self.bumper.as_mut())),
+            None => None,
+        }
+    }
+
+    /// Starts verifying that the process reloads the config after the bump. The verification is polled from the tick.
+    fn start_reload_verification(&mut self, snapshot: Option<Snapshot>) {
+        if let (Some(ref v), Some(snapshot)) = (&self.reload_verifier, snapshot) {
+            if self.reload.is_some() {
+                log::debug!(
+                    "The verification of the previous reload is superseded by the new bump."
+                );
+            }
+            log::debug!("Verifying that the process reloads the config.");
+            self.reload = Some(v.start(snapshot));
+        }
+    }
+
+    /// Polls the verification of the reload, reporting its outcome once it is known.
+    fn check_reload(&mut self) {
+        let result = match (&self.reload_verifier, &mut self.reload) {
+            (Some(v), Some(verification)) => v.poll(verification, self.bumper.as_mut()),
+            _ => return,
+        };
+
+        match result {
+            Ok(false) => return,
+            Ok(true) => {
+                log::info!("The process reloaded the config.");
+                self.metrics.reload_detected();
+                self.events.record(
+                    EventType::Normal,
+                    "Reloaded",
+                    "The process reloaded the config.",
+                );
+            }
+            Err(e) => {
+                log::warn!("{}", e);
+                self.metrics.reload_not_detected();
+                self.events
+                    .record(EventType::Warning, "ReloadNotDetected", &format!("{}", e));
+            }
+        }
+
+        self.reload = None;
+    }
+
+    /// Remembers the files of the config map as the last known good revision.
+    fn mark_good(&mut self, name: &str, files: Option<&ConfigFiles>) {
+        match files {
+            Some(files) => self.last_good.insert(name.to_owned(), files.clone()),
+            None => self.last_good.remove(name),
+        };
+    }
+
+    /// Schedules the check of the health of the process after the files of the config map have been applied. The
+    /// check waits for the bump, if it has been deferred, and then for the delay of the health
+    /// checker. Without a health checker, the files are good as soon as the process is bumped.
+    fn schedule_health_check(
+        &mut self,
+        name: &str,
+        applied: Option<&ConfigFiles>,
+        rollback: bool,
+        bumped: bool,
+    ) {
+        let due = match (&self.health_checker, bumped) {
+            (_, false) => None,
+            (Some(checker), true) => Some(Instant::now() + checker.delay()),
+            (None, true) => {
+                self.on_healthy(name, applied, rollback);
+                return;
+            }
+        };
+
+        self.pending.insert(
+            name.to_owned(),
+            PendingCheck {
+                applied: applied.cloned(),
+                due,
+                rollback,
+            },
+        );
+    }
+
+    /// Runs the health checks that are due, rolling back the config maps found unhealthy.
+    fn check_health(&mut self) {
+        let now = Instant::now();
+        let due: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, check)| check.due.map(|due| due <= now).unwrap_or(false))
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in due {
+            let check = match self.pending.remove(&name) {
+                Some(check) => check,
+                None => continue,
+            };
+            let result = match self.health_checker {
+                Some(ref checker) => checker.check(self.bumper.as_mut()),
+                None => Ok(()),
+            };
+
+            match result {
+                Ok(_) => self.on_healthy(&name, check.applied.as_ref(), check.rollback),
+                Err(e) if check.rollback => {
+                    let msg = format!(
+                        "The process is unhealthy even after rolling back config map `{}`: {}",
+                        name, e
+                    );
+                    log::error!("{}", msg);
+                    self.events
+                        .record(EventType::Warning, "RollbackUnhealthy", &msg);
+                }
+                Err(e) => {
+                    log::error!(
+                        "The process is unhealthy after applying config map `{}`: {}",
+                        name,
+                        e
+                    );
+                    if let Err(e) = self.rollback(&name, check.applied.as_ref()) {
+                        log::error!("Failed to roll back config map `{}`: {}", name, e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_healthy(&mut self, name: &str, applied: Option<&ConfigFiles>, rollback: bool) {
+        if rollback {
+            log::info!(
+                "The process is healthy after rolling back config map `{}`.",
+                name
+            );
+            return;
+        }
+
+        self.events.record(
+            EventType::Normal,
+            "ConfigApplied",
+            &format!("Config map `{}` applied.", name),
+        );
+        self.mark_good(name, applied);
+    }
+
+    /// Restores the last known good revision of the config map and bumps the process again.
+    fn rollback(
+        &mut self,
+        name: &str,
+        applied: Option<&ConfigFiles>,
+    ) -> Result<(), operator::Error> {
+        let good = match self.last_good.get(name) {
+            Some(good) => good.clone(),
+            None => {
+                let msg = format!(
+                    "No known good revision of config map `{}` to roll back to.",
+                    name
+                );
+                self.events
+                    .record(EventType::Warning, "RollbackImpossible", &msg);
+                return Err(operator::Error::OperatorError(msg));
+            }
+        };
+
+        if let Some(applied) = applied {
+            self.rejected.insert(name.to_owned(), digests(applied));
+        }
+
+        let (deleted, changed) = self.diff(applied, Some(&good));
+        let msg = format!(
+            "Rolling back config map `{}` to the last known good revision.",
+            name
+        );
+        log::warn!("{}", msg);
+        self.events.record(EventType::Warning, "RolledBack", &msg);
+
+        if self.apply(&deleted, &changed) {
+            self.bump_applied(name, Some(&good), true, &deleted, &changed);
+        }
+
+        Ok(())
+    }
 }
 
-impl operator::Operator<ConfigMap, ConfigFiles> for ConfigUpdater {
-    fn prepare(&self, cm: ConfigMap) -> ConfigFiles {
-        let cm_name = cm
-            .metadata
-            .map(|m| m.name)
-            .flatten()
-            .unwrap_or_else(|| "<unknown>".into());
+/// The digests of the files by their names.
+fn digests(files: &ConfigFiles) -> BTreeMap<String, String> {
+    files
+        .iter()
+        .map(|(name, file)| (name.clone(), file.digest.clone()))
+        .collect()
+}
+
+impl operator::Operator<ConfigMap, ConfigMapFiles> for ConfigUpdater {
+    fn prepare(&self, cm: ConfigMap) -> ConfigMapFiles {
+        let metadata = cm.metadata.unwrap_or_default();
+        let namespace = metadata.namespace.as_deref();
+        let cm_name = match (&metadata.name, namespace) {
+            (Some(name), Some(ns)) => format!("{}/{}", ns, name),
+            (Some(name), None) => name.clone(),
+            _ => "<unknown>".into(),
+        };
+
+        let annotations = metadata.annotations.unwrap_or_default();
+        let render = Renderer::is_enabled(&annotations);
+        let expand_env = template::is_expand_env_enabled(&annotations);
 
         log::debug!("Preparing config map {} for caching.", cm_name);
 
         let mut files = ConfigFiles::new();
+        let mut error = None;
+
+        // none of the keys can be told to be wanted with invalid filter annotations
+        let cm_key_filter = match KeyFilter::from_annotations(&annotations) {
+            Ok(filter) => filter,
+            Err(e) => {
+                return ConfigMapFiles {
+                    error: Some(PrepareError {
+                        reason: "InvalidKeyFilter",
+                        message: format!(
+                            "Invalid key filter annotations of config map `{}`: {}",
+                            cm_name, e
+                        ),
+                    }),
+                    name: cm_name,
+                    files,
+                };
+            }
+        };
 
         let mut sha = sha1::Sha1::new();
 
         if let Some(data) = cm.data {
-            for (name, data) in data {
+            for (name, value) in &data {
+                if !self.key_filter.matches(name)
+                    || !cm_key_filter
+                        .as_ref()
+                        .map(|f| f.matches(name))
+                        .unwrap_or(true)
+                {
+                    log::debug!("Skipping file {} excluded by the key filter", name);
+                    continue;
+                }
+
                 log::debug!("Adding file {}", name);
+                let data = if error.is_none() {
+                    match self.render(value, namespace, &data, expand_env, render) {
+                        Ok(rendered) => rendered,
+                        Err(e) => {
+                            error = Some(PrepareError {
+                                reason: "TemplateFailed",
+                                message: format!(
+                                    "Failed to render `{}` of config map `{}`: {}",
+                                    name, cm_name, e
+                                ),
+                            });
+                            value.clone()
+                        }
+                    }
+                } else {
+                    value.clone()
+                };
+
                 sha.reset();
                 sha.update(data.as_bytes());
 
@@ -79,95 +773,517 @@ impl operator::Operator<ConfigMap, ConfigFiles> for ConfigUpdater {
                     digest: sha.digest().to_string(),
                 };
 
-                files.insert(name, file);
+                files.insert(name.clone(), file);
             }
         }
 
         log::debug!("Preparing config map {} for caching.", cm_name);
 
-        files
+        ConfigMapFiles {
+            name: cm_name,
+            files,
+            error,
+        }
+    }
+
+    fn tick(&mut self) -> Result<(), operator::Error> {
+        let result = self.bump_deferred();
+        self.check_reload();
+        self.check_health();
+        result
     }
 
     fn reconcile(
         &mut self,
-        old: Option<&ConfigFiles>,
-        new: Option<&ConfigFiles>,
+        old: Option<&ConfigMapFiles>,
+        new: Option<&ConfigMapFiles>,
     ) -> Result<(), operator::Error> {
         log::debug!("Reconciling {:?} with {:?}", old, new);
-        let mut updated = false;
 
-        // first let's delete all the files from old that are not in new
-        if let Some(old_files) = old {
-            let empty = ConfigFiles::new();
-            for f in old_files.keys() {
-                if !new.unwrap_or(&empty).contains_key(f) {
-                    let path = self.to_path(f);
-                    log::debug!("Deleting config file {:?}", path);
-                    match std::fs::remove_file(path) {
-                        Err(e) => {
-                            log::error!(
-                                "Failed to delete a no longer required config file `{}`: {}",
-                                f,
-                                e
-                            );
-                        }
-                        _ => {
-                            updated = true;
-                        }
-                    }
-                }
+        let name = match new.or(old) {
+            Some(cm) => cm.name.clone(),
+            None => return Ok(()),
+        };
+        if let Some(error) = new.and_then(|n| n.error.as_ref()) {
+            log::error!("{}", error.message);
+            self.events
+                .record(EventType::Warning, error.reason, &error.message);
+            return Err(operator::Error::OperatorError(format!(
+                "Refusing to apply config map `{}` that failed to be prepared.",
+                name
+            )));
+        }
+        let old = old.map(|o| &o.files);
+        let new = new.map(|n| &n.files);
+
+        match new {
+            Some(new) if self.rejected.get(&name) == Some(&digests(new)) => {
+                log::debug!(
+                    "Config map `{}` is the revision that has been rolled back. Not applying it again.",
+                    name
+                );
+                return Ok(());
+            }
+            _ => {
+                self.rejected.remove(&name);
             }
         }
 
-        if let Some(new_files) = new {
-            let mut sha = sha1::Sha1::new();
-
-            // now let's create or update all the files that should be there according to the new config
-            for (name, cfg) in new_files {
-                let path = self.to_path(name);
-                if path.exists() {
-                    match std::fs::read(path.clone()) {
-                        Ok(data) => {
-                            sha.reset();
-                            sha.update(&data);
-                            let digest = sha.digest().to_string();
-                            if digest == cfg.digest {
-                                log::debug!(
-                                    "Config file `{}` hasn't changed. Skipping update.",
-                                    name
-                                );
-                                continue;
-                            }
-                        }
-                        Err(e) => {
-                            log::warn!("Will overwrite the config file `{}` forcefully because of failure to compute its checksum: {}", name, e);
-                        }
-                    }
-                }
+        if let Some(new) = new {
+            self.check_conflicts(&name, new)?;
+        }
 
-                match std::fs::write(path, cfg.content.as_bytes()) {
-                    Ok(_) => {
-                        log::debug!("Updated the config file `{}`", name);
-                        updated = true;
-                    }
-                    Err(e) => {
-                        log::error!("Failed to update the config file `{}`: {}", name, e);
-                    }
+        let (deleted, changed) = self.diff(old, new);
+
+        if deleted.is_empty() && changed.is_empty() {
+            log::debug!("No changes to config files found.");
+            self.own(&name, old, new);
+            // what is on disk is what has been running so far, so it's the best candidate to roll back to
+            if let Some(new) = new {
+                if !self.last_good.contains_key(&name) {
+                    self.mark_good(&name, Some(new));
                 }
             }
+            return Ok(());
+        }
+
+        if let Some(ref validator) = self.validator {
+            log::debug!("Validating the config changes before applying them.");
+            validator
+                .validate(std::path::Path::new(&self.dir), &deleted, &changed)
+                .map_err(|e| {
+                    log::error!("Refusing to apply the config changes: {}", e);
+                    operator::Error::OperatorError(format!("{}", e))
+                })?;
         }
 
-        if updated {
+        self.own(&name, old, new);
+        if self.apply(&deleted, &changed) {
             log::debug!("Updates to the config files applied.");
-            if let Some(ref mut b) = self.bumper {
-                log::debug!("Bumping the configured process.");
-                b.bump()
-                    .map_err(|e| operator::Error::OperatorError(format!("{}", e)))?;
-            }
+            self.bump_applied(&name, new, false, &deleted, &changed);
         } else {
-            log::debug!("No changes to config files found.");
+            log::debug!("No changes to config files could be applied.");
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::health::HealthCheck;
+    use crate::operator::Operator;
+    use std::time::Duration;
+
+    fn config_map(name: &str, content: &str) -> ConfigMapFiles {
+        let mut sha = sha1::Sha1::new();
+        sha.update(content.as_bytes());
+        let mut files = ConfigFiles::new();
+        files.insert(
+            "conf".into(),
+            ConfigFile {
+                content: content.into(),
+                digest: sha.digest().to_string(),
+            },
+        );
+        ConfigMapFiles {
+            name: name.into(),
+            files,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_rollback_on_failed_health_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = dir.path().join("conf");
+        let checker = HealthChecker::new(
+            vec![HealthCheck::Command(format!(
+                "! grep -q broken {}",
+                conf.to_string_lossy()
+            ))],
+            Duration::from_millis(0),
+        )
+        .unwrap();
+
+        let mut updater = ConfigUpdater::new(&dir.path().to_string_lossy(), None)
+            .unwrap()
+            .with_health_checker(checker);
+
+        let good = config_map("cm", "good");
+        let broken = config_map("cm", "broken");
+
+        updater.reconcile(None, Some(&good)).unwrap();
+        updater.tick().unwrap();
+        assert_eq!("good", std::fs::read_to_string(&conf).unwrap());
+
+        // the check is done on the next tick after the delay, not while reconciling
+        updater.reconcile(Some(&good), Some(&broken)).unwrap();
+        assert_eq!("broken", std::fs::read_to_string(&conf).unwrap());
+        updater.tick().unwrap();
+        assert_eq!("good", std::fs::read_to_string(&conf).unwrap());
+
+        // the rollback is checked, too
+        assert!(updater.pending["cm"].rollback);
+        updater.tick().unwrap();
+        assert!(updater.pending.is_empty());
+
+        // the rolled back revision isn't applied again until the config map changes, e.g. on a resync
+        updater.reconcile(Some(&broken), Some(&broken)).unwrap();
+        assert_eq!("good", std::fs::read_to_string(&conf).unwrap());
+        assert!(updater.pending.is_empty());
+
+        let fixed = config_map("cm", "fixed");
+        updater.reconcile(Some(&broken), Some(&fixed)).unwrap();
+        assert_eq!("fixed", std::fs::read_to_string(&conf).unwrap());
+    }
+
+    #[test]
+    fn test_unhealthy_rollback_not_rolled_back_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = dir.path().join("conf");
+        let checker = HealthChecker::new(
+            vec![HealthCheck::Command(format!(
+                "grep -q healthy {}",
+                conf.to_string_lossy()
+            ))],
+            Duration::from_millis(0),
+        )
+        .unwrap();
+
+        let mut updater = ConfigUpdater::new(&dir.path().to_string_lossy(), None)
+            .unwrap()
+            .with_health_checker(checker);
+
+        let good = config_map("cm", "healthy");
+        let broken = config_map("cm", "broken");
+
+        updater.reconcile(None, Some(&good)).unwrap();
+        updater.tick().unwrap();
+        updater.reconcile(Some(&good), Some(&broken)).unwrap();
+
+        // the process became unhealthy regardless of the config
+        updater.health_checker = HealthChecker::new(
+            vec![HealthCheck::Command("false".into())],
+            Duration::from_millis(0),
+        )
+        .ok();
+        updater.tick().unwrap();
+        assert_eq!("healthy", std::fs::read_to_string(&conf).unwrap());
+        updater.tick().unwrap();
+        assert!(updater.pending.is_empty());
+        assert_eq!("healthy", std::fs::read_to_string(&conf).unwrap());
+    }
+
+    #[test]
+    fn test_failed_bump_retried_from_tick() {
+        use std::io::{BufRead, BufReader, Write};
+
+        let dir = tempfile::tempdir().unwrap();
+        let files = dir.path().join("files");
+        std::fs::create_dir(&files).unwrap();
+        let socket = dir.path().join("admin.sock");
+        let socket_bumper = SocketBumper::new(&socket, &["set conf {file}".into()], &[]).unwrap();
+        let mut updater = ConfigUpdater::new(&files.to_string_lossy(), None)
+            .unwrap()
+            .with_socket_bumper(socket_bumper);
+
+        // the files are on disk, so the config map counts as applied even though the socket isn't there yet
+        let cm = config_map("cm", "v1");
+        updater.reconcile(None, Some(&cm)).unwrap();
+        assert_eq!("v1", std::fs::read_to_string(files.join("conf")).unwrap());
+        assert!(updater.is_bump_deferred());
+
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut stream = listener.incoming().next().unwrap().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            stream.write_all(b"\n").unwrap();
+            line
+        });
+
+        updater.tick().unwrap();
+        assert!(!updater.is_bump_deferred());
+        assert_eq!(
+            format!("set conf {}\n", files.join("conf").to_string_lossy()),
+            server.join().unwrap()
+        );
+
+        updater.reconcile(Some(&cm), None).unwrap();
+        assert!(!files.join("conf").exists());
+    }
+
+    #[test]
+    fn test_same_file_from_other_config_map_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = dir.path().join("conf");
+        let mut updater = ConfigUpdater::new(&dir.path().to_string_lossy(), None).unwrap();
+
+        let a = config_map("platform-config/nginx", "a");
+        let b = config_map("app/nginx", "b");
+
+        updater.reconcile(None, Some(&a)).unwrap();
+        assert!(updater.reconcile(None, Some(&b)).is_err());
+        assert_eq!("a", std::fs::read_to_string(&conf).unwrap());
+
+        // the same content doesn't make it any less of a conflict
+        assert!(updater
+            .reconcile(None, Some(&config_map("app/nginx", "a")))
+            .is_err());
+
+        // the file can come from the other config map once the first one no longer has it
+        updater.reconcile(Some(&a), None).unwrap();
+        updater.reconcile(None, Some(&b)).unwrap();
+        assert_eq!("b", std::fs::read_to_string(&conf).unwrap());
+    }
+
+    #[test]
+    fn test_reload_verified_from_tick() {
+        use crate::reload::ReloadCheck;
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let logs = tempfile::tempdir().unwrap();
+        let log = logs.path().join("access.log");
+        std::fs::write(&log, "").unwrap();
+        let verifier = ReloadVerifier::new(
+            vec![ReloadCheck::LogLine(
+                log.clone(),
+                regex::Regex::new("reloaded").unwrap(),
+            )],
+            Duration::from_secs(10),
+        )
+        .unwrap();
+
+        let metrics = Arc::new(Metrics::default());
+        let mut updater = ConfigUpdater::new(&dir.path().to_string_lossy(), None)
+            .unwrap()
+            .with_reload_verifier(verifier)
+            .with_metrics(metrics.clone());
+
+        updater
+            .reconcile(None, Some(&config_map("cm", "v1")))
+            .unwrap();
+        updater.tick().unwrap();
+        assert!(updater.reload.is_some());
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&log)
+            .unwrap()
+            .write_all(b"reloaded\n")
+            .unwrap();
+        updater.tick().unwrap();
+        assert!(updater.reload.is_none());
+        assert!(metrics
+            .render()
+            .contains("cm_bump_reloads_total{result=\"detected\"} 1\n"));
+    }
+
+    #[test]
+    fn test_bumps_merged_when_rate_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let proc = tempfile::tempdir().unwrap();
+        let bumper = Bumper::new(vec![crate::bumper::ProcessDetection::Pid(4242)], "SIGHUP")
+            .unwrap()
+            .with_proc(crate::procfs::ProcFs::new(proc.path()))
+            .with_rate_limiter(crate::ratelimit::RateLimiter::new(Duration::from_secs(
+                3600,
+            )));
+
+        let mut updater = ConfigUpdater::new(&dir.path().to_string_lossy(), Some(bumper)).unwrap();
+
+        let v1 = config_map("cm", "v1");
+        let v2 = config_map("cm", "v2");
+        let v3 = config_map("cm", "v3");
+
+        updater.reconcile(None, Some(&v1)).unwrap();
+        assert!(updater.pending.is_empty());
+
+        updater.reconcile(Some(&v1), Some(&v2)).unwrap();
+        updater.reconcile(Some(&v2), Some(&v3)).unwrap();
+        updater.tick().unwrap();
+
+        // the files are written right away, only the bump waits
+        assert_eq!(
+            "v3",
+            std::fs::read_to_string(dir.path().join("conf")).unwrap()
+        );
+        assert!(updater.bumper.as_ref().unwrap().is_deferred());
+        assert_eq!(vec!["cm"], updater.pending.keys().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_deferred_bump_fired_from_tick_after_refill() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let proc = tempfile::tempdir().unwrap();
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let pid = child.id() as i32;
+
+        let bumper = Bumper::new(vec![crate::bumper::ProcessDetection::Pid(pid)], "SIGTERM")
+            .unwrap()
+            .with_proc(crate::procfs::ProcFs::new(proc.path()))
+            .with_rate_limiter(
+                crate::ratelimit::RateLimiter::new(Duration::from_secs(0))
+                    .with_token_bucket(1, Duration::from_millis(300)),
+            );
+        let mut updater = ConfigUpdater::new(&dir.path().to_string_lossy(), Some(bumper)).unwrap();
+
+        // the only token is taken by a bump while the process isn't visible yet
+        updater.bumper.as_mut().unwrap().bump().unwrap();
+        std::fs::create_dir(proc.path().join(pid.to_string())).unwrap();
+
+        updater
+            .reconcile(None, Some(&config_map("cm", "v1")))
+            .unwrap();
+        assert!(updater.bumper.as_ref().unwrap().is_deferred());
+        updater.tick().unwrap();
+        assert!(child.try_wait().unwrap().is_none());
+        assert!(updater.pending["cm"].due.is_none());
+
+        std::thread::sleep(Duration::from_millis(300));
+        updater.tick().unwrap();
+        assert!(!updater.bumper.as_ref().unwrap().is_deferred());
+        assert!(updater.pending.is_empty());
+
+        let status = child.wait().unwrap();
+        assert_eq!(
+            Some(nix::sys::signal::Signal::SIGTERM as i32),
+            status.signal()
+        );
+    }
+
+    #[test]
+    fn test_invalid_key_filter_fails_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut updater = ConfigUpdater::new(&dir.path().to_string_lossy(), None).unwrap();
+
+        let mut data = BTreeMap::new();
+        data.insert("nginx.conf".to_string(), "server {}".to_string());
+        data.insert("secret.key".to_string(), "private".to_string());
+        let mut annotations = BTreeMap::new();
+        annotations.insert(
+            crate::keys::INCLUDE_ANNOTATION.to_string(),
+            "nginx.[conf".to_string(),
+        );
+        let cm = ConfigMap {
+            metadata: Some(k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta {
+                name: Some("nginx".into()),
+                annotations: Some(annotations),
+                ..Default::default()
+            }),
+            data: Some(data),
+            ..ConfigMap::default()
+        };
+
+        let files = updater.prepare(cm);
+        assert_eq!(
+            Some("InvalidKeyFilter"),
+            files.error.as_ref().map(|e| e.reason)
+        );
+        assert!(updater.reconcile(None, Some(&files)).is_err());
+        assert!(!dir.path().join("nginx.conf").exists());
+        assert!(!dir.path().join("secret.key").exists());
+    }
+
+    #[test]
+    fn test_env_expanded_without_rendering_the_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut updater = ConfigUpdater::new(&dir.path().to_string_lossy(), None).unwrap();
+        std::env::set_var("CM_BUMP_TEST_EXPANDED", "{{ key.ttl }}");
+
+        let mut data = BTreeMap::new();
+        data.insert(
+            "root.conf".to_string(),
+            "# ${CM_BUMP_TEST_EXPANDED}\nresolver 10.0.0.10 valid={{ key.ttl }};".to_string(),
+        );
+        data.insert("ttl".to_string(), "1m".to_string());
+        let mut annotations = BTreeMap::new();
+        annotations.insert(
+            crate::template::TEMPLATE_ANNOTATION.to_string(),
+            "true".to_string(),
+        );
+        annotations.insert(
+            crate::template::EXPAND_ENV_ANNOTATION.to_string(),
+            "true".to_string(),
+        );
+        let cm = ConfigMap {
+            metadata: Some(k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta {
+                name: Some("nginx".into()),
+                annotations: Some(annotations),
+                ..Default::default()
+            }),
+            data: Some(data),
+            ..ConfigMap::default()
+        };
+
+        let files = updater.prepare(cm);
+        updater.reconcile(None, Some(&files)).unwrap();
+        assert_eq!(
+            "# {{ key.ttl }}\nresolver 10.0.0.10 valid=1m;",
+            std::fs::read_to_string(dir.path().join("root.conf")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_templates_rendered_strictly() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = dir.path().join("root.conf");
+        let mut updater = ConfigUpdater::new(&dir.path().to_string_lossy(), None).unwrap();
+
+        let template = |ttl: Option<&str>| {
+            let mut data = BTreeMap::new();
+            data.insert(
+                "root.conf".to_string(),
+                "resolver 10.0.0.10 valid={{ key.ttl }};".to_string(),
+            );
+            if let Some(ttl) = ttl {
+                data.insert("ttl".to_string(), ttl.to_string());
+            }
+            let mut annotations = BTreeMap::new();
+            annotations.insert(
+                crate::template::TEMPLATE_ANNOTATION.to_string(),
+                "true".to_string(),
+            );
+            ConfigMap {
+                metadata: Some(k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta {
+                    name: Some("nginx".into()),
+                    annotations: Some(annotations),
+                    ..Default::default()
+                }),
+                data: Some(data),
+                ..ConfigMap::default()
+            }
+        };
+
+        let v1 = updater.prepare(template(Some("1m")));
+        updater.reconcile(None, Some(&v1)).unwrap();
+        assert_eq!(
+            "resolver 10.0.0.10 valid=1m;",
+            std::fs::read_to_string(&conf).unwrap()
+        );
+
+        // the files of a config map failing to render are left alone
+        let broken = updater.prepare(template(None));
+        assert!(broken.error.is_some());
+        assert!(updater.reconcile(Some(&v1), Some(&broken)).is_err());
+        assert_eq!(
+            "resolver 10.0.0.10 valid=1m;",
+            std::fs::read_to_string(&conf).unwrap()
+        );
+
+        let v2 = updater.prepare(template(Some("5m")));
+        updater.reconcile(Some(&broken), Some(&v2)).unwrap();
+        assert_eq!(
+            "resolver 10.0.0.10 valid=5m;",
+            std::fs::read_to_string(&conf).unwrap()
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use super::shell;
+use super::updater::ConfigFile;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+use thiserror::Error;
+
+/// The placeholder in the validation command that is replaced by the path to the staging directory.
+pub const DIR_PLACEHOLDER: &str = "{dir}";
+
+/// The environment variable that the validation command can use to find the staging directory.
+pub const DIR_ENV_VAR: &str = "CM_STAGING_DIR";
+
+#[derive(Debug, Clone, Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    #[error("Failed to prepare the staging directory: {0}")]
+    StagingError(String),
+
+    #[error("Failed to run the validation command: {0}")]
+    CommandError(String),
+
+    #[error("Validation failed: {0}")]
+    ValidationError(String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Runs a command against a staged copy of the config directory with the pending changes applied.
+/// The changes are only committed to the real directory if the command succeeds.
+#[derive(Debug, Clone)]
+pub struct Validator {
+    command: String,
+    timeout: Duration,
+}
+
+impl Validator {
+    pub fn new(command: &str) -> Self {
+        Validator {
+            command: command.to_owned(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Sets how long the validation command may run. A command still running after the timeout is killed and the
+    /// validation fails.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Copies the contents of `base_dir` into a temporary staging directory, removes the `deleted` files and writes
+    /// the `changed` files there and runs the validation command against it.
+    pub fn validate(
+        &self,
+        base_dir: &Path,
+        deleted: &[&String],
+        changed: &[(&String, &ConfigFile)],
+    ) -> Result<()> {
+        let staging = tempfile::Builder::new()
+            .prefix("cm-bump-staging")
+            .tempdir()
+            .map_err(|e| staging_error(&e))?;
+
+        log::debug!("Staging the config changes in {:?}", staging.path());
+
+        copy_dir(base_dir, staging.path())?;
+
+        for name in deleted {
+            let path = staging.path().join(name);
+            if path.exists() {
+                fs::remove_file(path).map_err(|e| staging_error(&e))?;
+            }
+        }
+
+        for (name, cfg) in changed {
+            fs::write(staging.path().join(name), cfg.content.as_bytes())
+                .map_err(|e| staging_error(&e))?;
+        }
+
+        let staging_path = staging.path().to_string_lossy();
+        let command = self.command.replace(DIR_PLACEHOLDER, &staging_path);
+
+        log::debug!("Running the validation command `{}`", command);
+
+        let output = shell::output(
+            Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .env(DIR_ENV_VAR, staging.path()),
+            self.timeout,
+        )
+        .map_err(|e| Error::CommandError(format!("`{}` {}", command, e)))?;
+
+        if output.status.success() {
+            log::debug!("The validation command `{}` succeeded.", command);
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            Err(Error::ValidationError(format!(
+                "`{}` exited with {}. stdout: `{}`, stderr: `{}`",
+                command,
+                output.status,
+                stdout.trim(),
+                stderr.trim()
+            )))
+        }
+    }
+}
+
+fn staging_error(e: &dyn ToString) -> Error {
+    Error::StagingError(e.to_string())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in fs::read_dir(from).map_err(|e| staging_error(&e))? {
+        let entry = entry.map_err(|e| staging_error(&e))?;
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type().map_err(|e| staging_error(&e))?;
+        if file_type.is_dir() {
+            fs::create_dir(&target).map_err(|e| staging_error(&e))?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target).map_err(|e| staging_error(&e))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file(content: &str) -> ConfigFile {
+        ConfigFile {
+            content: content.into(),
+            digest: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_validation_sees_staged_changes() {
+        let base = tempfile::tempdir().unwrap();
+        fs::write(base.path().join("old"), "old").unwrap();
+        fs::write(base.path().join("kept"), "kept").unwrap();
+
+        let new_name = "new".to_string();
+        let new_file = file("ok");
+        let old_name = "old".to_string();
+
+        let validator = Validator::new(
+            "test ! -e {dir}/old && test -f $CM_STAGING_DIR/kept && grep -q ok {dir}/new",
+        );
+
+        validator
+            .validate(base.path(), &[&old_name], &[(&new_name, &new_file)])
+            .unwrap();
+
+        // the real directory must stay untouched
+        assert!(base.path().join("old").exists());
+        assert!(!base.path().join("new").exists());
+    }
+
+    #[test]
+    fn test_validation_failure() {
+        let base = tempfile::tempdir().unwrap();
+        let name = "nginx.conf".to_string();
+        let broken = file("broken");
+
+        let validator = Validator::new("grep -q valid {dir}/nginx.conf");
+
+        match validator.validate(base.path(), &[], &[(&name, &broken)]) {
+            Err(Error::ValidationError(_)) => {}
+            r => panic!("Unexpected validation result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_validation_timeout() {
+        let base = tempfile::tempdir().unwrap();
+        let validator = Validator::new("sleep 30").with_timeout(Duration::from_millis(200));
+
+        match validator.validate(base.path(), &[], &[]) {
+            Err(Error::CommandError(_)) => {}
+            r => panic!("Unexpected validation result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! End-to-end tests running cm-bump against a fake Kubernetes API server.
+
+mod support;
+
+use support::*;
+
+#[test]
+fn test_files_written_and_process_bumped() {
+    let server = FakeApiServer::start();
+    server.update(|s| s.apply(config_map("app", "nginx", &[("nginx.conf", "v1")])));
+
+    let workdir = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let signal_log = workdir.path().join("signals");
+    let recorder = signal_recorder(&signal_log);
+    let pid = recorder.0.id().to_string();
+
+    let _cm_bump = cm_bump(
+        &server,
+        workdir.path(),
+        &[
+            "--namespace",
+            "app",
+            "--dir",
+            out.path().to_str().unwrap(),
+            "--process-pid",
+            &pid,
+            "--signal",
+            "SIGHUP",
+        ],
+    );
+
+    let conf = out.path().join("nginx.conf");
+    eventually("the initial file", || read(&conf).as_deref() == Some("v1"));
+    eventually("the initial bump", || signals(&signal_log) == 1);
+
+    server.update(|s| s.apply(config_map("app", "nginx", &[("nginx.conf", "v2")])));
+    eventually("the updated file", || read(&conf).as_deref() == Some("v2"));
+    eventually("the bump after the update", || signals(&signal_log) == 2);
+
+    // config maps in other namespaces are not watched
+    server.update(|s| s.apply(config_map("other", "haproxy", &[("haproxy.cfg", "v1")])));
+    server.update(|s| s.delete("app", "nginx"));
+    eventually("the deleted file", || !conf.exists());
+    assert!(!out.path().join("haproxy.cfg").exists());
+}
+
+#[test]
+fn test_resync_after_expired_watch() {
+    let server = FakeApiServer::start();
+    server.update(|s| {
+        s.apply(config_map("app", "a", &[("a.conf", "v1")]));
+        s.apply(config_map("app", "b", &[("b.conf", "v1")]));
+    });
+
+    let workdir = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let _cm_bump = cm_bump(
+        &server,
+        workdir.path(),
+        &["--namespace", "app", "--dir", out.path().to_str().unwrap()],
+    );
+
+    let a = out.path().join("a.conf");
+    let b = out.path().join("b.conf");
+    eventually("the initial files", || a.exists() && b.exists());
+    eventually("the watch", || {
+        server.update(|s| s.watches("configmaps")) == 1
+    });
+
+    // the changes are lost, only the relist can find out about them
+    server.update(|s| {
+        s.delete("app", "b");
+        s.apply(config_map("app", "a", &[("a.conf", "v2")]));
+        s.expire();
+    });
+
+    eventually("the relist", || {
+        server.update(|s| s.lists("configmaps")) == 2
+    });
+    eventually("the deleted file", || !b.exists());
+    eventually("the updated file", || read(&a).as_deref() == Some("v2"));
+}
+
+#[test]
+fn test_watch_resumed_after_disconnect() {
+    let server = FakeApiServer::start();
+    server.update(|s| s.apply(config_map("app", "nginx", &[("nginx.conf", "v1")])));
+
+    let workdir = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let _cm_bump = cm_bump(
+        &server,
+        workdir.path(),
+        &["--namespace", "app", "--dir", out.path().to_str().unwrap()],
+    );
+
+    let conf = out.path().join("nginx.conf");
+    eventually("the initial file", || read(&conf).as_deref() == Some("v1"));
+    eventually("the watch", || {
+        server.update(|s| s.watches("configmaps")) == 1
+    });
+
+    server.update(|s| s.disconnect());
+    eventually("the resumed watch", || {
+        server.update(|s| s.watches("configmaps")) == 2
+    });
+
+    server.update(|s| s.apply(config_map("app", "nginx", &[("nginx.conf", "v2")])));
+    eventually("the updated file", || read(&conf).as_deref() == Some("v2"));
+    assert_eq!(1, server.update(|s| s.lists("configmaps")));
+}
+
+#[test]
+fn test_secrets_in_templates() {
+    let server = FakeApiServer::start();
+    let mut cm = config_map(
+        "app",
+        "db",
+        &[("db.conf", "user app\npassword {{ secret.db.password }}\n")],
+    );
+    cm.metadata.as_mut().unwrap().annotations = Some(
+        vec![("cm-bump/template".to_string(), "true".to_string())]
+            .into_iter()
+            .collect(),
+    );
+    server.update(|s| {
+        s.apply_secret(secret("app", "db", &[("password", "first-s3cr3t")]));
+        s.apply(cm);
+    });
+
+    let workdir = tempfile::tempdir().unwrap();
+    let out = tempfile::tempdir().unwrap();
+    let log = workdir.path().join("cm-bump.log");
+    let cm_bump = cm_bump_logged(
+        &server,
+        workdir.path(),
+        &["--namespace", "app", "--dir", out.path().to_str().unwrap()],
+        &log,
+    );
+
+    let conf = out.path().join("db.conf");
+    eventually("the rendered file", || {
+        read(&conf).as_deref() == Some("user app\npassword first-s3cr3t\n")
+    });
+    eventually("the watch of the secret", || {
+        server.update(|s| s.watches("secrets")) == 1
+    });
+
+    server.update(|s| s.apply_secret(secret("app", "db", &[("password", "second-s3cr3t")])));
+    eventually("the file rendered again", || {
+        read(&conf).as_deref() == Some("user app\npassword second-s3cr3t\n")
+    });
+
+    drop(cm_bump);
+    let log = read(&log).unwrap();
+    assert!(log.contains("Secret `app/db` changed."));
+    assert!(!log.contains("s3cr3t"));
+}
@@ -0,0 +1,507 @@
+//! An in-process fake of the Kubernetes API server serving get, list and watch of config maps and secrets, and the
+//! helpers to run cm-bump against it.
+
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use k8s_openapi::ByteString;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The state of the fake server. Every change is assigned a new resource version and is sent to the open watches.
+#[derive(Default)]
+pub struct State {
+    version: u64,
+    /// The objects by their resource, e.g. `configmaps`, and `namespace/name`.
+    objects: BTreeMap<(String, String), Value>,
+    history: Vec<(u64, &'static str, String, Value)>,
+    /// The versions up to this one are no longer in the history, watching from them results in a 410.
+    compacted: u64,
+    /// Increased to make the open watches fail with a 410.
+    expirations: u64,
+    /// Increased to close the open watches.
+    disconnects: u64,
+    lists: BTreeMap<String, usize>,
+    watches: BTreeMap<String, usize>,
+}
+
+impl State {
+    /// Creates or modifies the config map.
+    pub fn apply(&mut self, cm: ConfigMap) {
+        self.put("configmaps", serde_json::to_value(cm).unwrap());
+    }
+
+    pub fn delete(&mut self, namespace: &str, name: &str) {
+        self.remove("configmaps", namespace, name);
+    }
+
+    /// Creates or modifies the secret.
+    pub fn apply_secret(&mut self, secret: Secret) {
+        self.put("secrets", serde_json::to_value(secret).unwrap());
+    }
+
+    fn put(&mut self, resource: &str, mut object: Value) {
+        self.version += 1;
+        let key = (resource.to_owned(), key(&object));
+        let uid = format!("uid-{}-{}", key.0, key.1);
+        let meta = &mut object["metadata"];
+        meta["resourceVersion"] = Value::String(self.version.to_string());
+        if meta.get("uid").is_none() {
+            meta["uid"] = Value::String(uid);
+        }
+
+        let kind = if self.objects.contains_key(&key) {
+            "MODIFIED"
+        } else {
+            "ADDED"
+        };
+        self.objects.insert(key, object.clone());
+        self.history
+            .push((self.version, kind, resource.to_owned(), object));
+    }
+
+    fn remove(&mut self, resource: &str, namespace: &str, name: &str) {
+        let key = (resource.to_owned(), format!("{}/{}", namespace, name));
+        if let Some(mut object) = self.objects.remove(&key) {
+            self.version += 1;
+            object["metadata"]["resourceVersion"] = Value::String(self.version.to_string());
+            self.history
+                .push((self.version, "DELETED", resource.to_owned(), object));
+        }
+    }
+
+    /// Compacts the history. The open watches fail with a 410 instead of receiving the changes not yet sent to them.
+    pub fn expire(&mut self) {
+        self.history.clear();
+        self.compacted = self.version;
+        self.expirations += 1;
+    }
+
+    /// Closes the open watches.
+    pub fn disconnect(&mut self) {
+        self.disconnects += 1;
+    }
+
+    /// The number of list requests of the resource served.
+    pub fn lists(&self, resource: &str) -> usize {
+        self.lists.get(resource).cloned().unwrap_or(0)
+    }
+
+    /// The number of watch requests of the resource served.
+    pub fn watches(&self, resource: &str) -> usize {
+        self.watches.get(resource).cloned().unwrap_or(0)
+    }
+}
+
+pub struct FakeApiServer {
+    addr: SocketAddr,
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl FakeApiServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new((Mutex::new(State::default()), Condvar::new()));
+
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = server_state.clone();
+                thread::spawn(move || serve(stream, &state));
+            }
+        });
+
+        FakeApiServer { addr, state }
+    }
+
+    /// Changes the state of the server. All the changes are seen by the watches at once.
+    pub fn update<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        let (lock, changed) = &*self.state;
+        let result = f(&mut lock.lock().unwrap());
+        changed.notify_all();
+        result
+    }
+
+    /// Writes a kubeconfig pointing to the server into the directory.
+    pub fn kubeconfig(&self, dir: &Path) -> PathBuf {
+        let path = dir.join("kubeconfig");
+        fs::write(
+            &path,
+            format!(
+                "apiVersion: v1\nkind: Config\n\
+                 clusters:\n- name: fake\n  cluster:\n    server: http://{}\n\
+                 users:\n- name: fake\n  user:\n    token: fake\n\
+                 contexts:\n- name: fake\n  context:\n    cluster: fake\n    user: fake\n\
+                 current-context: fake\n",
+                self.addr
+            ),
+        )
+        .unwrap();
+        path
+    }
+}
+
+fn key(object: &Value) -> String {
+    format!(
+        "{}/{}",
+        object["metadata"]["namespace"].as_str().unwrap_or_default(),
+        object["metadata"]["name"].as_str().unwrap_or_default()
+    )
+}
+
+pub fn config_map(namespace: &str, name: &str, data: &[(&str, &str)]) -> ConfigMap {
+    ConfigMap {
+        metadata: Some(ObjectMeta {
+            name: Some(name.into()),
+            namespace: Some(namespace.into()),
+            ..ObjectMeta::default()
+        }),
+        data: Some(
+            data.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        ),
+        ..ConfigMap::default()
+    }
+}
+
+pub fn secret(namespace: &str, name: &str, data: &[(&str, &str)]) -> Secret {
+    Secret {
+        metadata: Some(ObjectMeta {
+            name: Some(name.into()),
+            namespace: Some(namespace.into()),
+            ..ObjectMeta::default()
+        }),
+        data: Some(
+            data.iter()
+                .map(|(k, v)| (k.to_string(), ByteString(v.as_bytes().to_vec())))
+                .collect(),
+        ),
+        ..Secret::default()
+    }
+}
+
+/// Serves the requests on the connection until it is closed or a watch is finished.
+fn serve(stream: TcpStream, state: &(Mutex<State>, Condvar)) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).unwrap_or(0) == 0 {
+                return;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let mut parts = header.splitn(2, ':');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let target = parts.next().unwrap_or_default();
+        let (path, query) = match target.find('?') {
+            Some(idx) => (&target[..idx], &target[idx + 1..]),
+            None => (target, ""),
+        };
+        let params: BTreeMap<&str, &str> = query
+            .split('&')
+            .filter_map(|p| {
+                let mut kv = p.splitn(2, '=');
+                Some((kv.next()?, kv.next().unwrap_or_default()))
+            })
+            .collect();
+
+        let query = match (method, Query::parse(path, &params)) {
+            ("GET", Some(query)) => query,
+            _ => {
+                respond(&mut stream, "404 Not Found", "{}");
+                continue;
+            }
+        };
+
+        if params.get("watch") == Some(&"true") {
+            let version = params
+                .get("resourceVersion")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0);
+            watch(&mut stream, state, &query, version);
+            return;
+        } else if query.get {
+            match get(state, &query) {
+                Some(object) => respond(&mut stream, "200 OK", &object),
+                None => respond(
+                    &mut stream,
+                    "404 Not Found",
+                    r#"{"kind": "Status", "status": "Failure", "reason": "NotFound", "code": 404}"#,
+                ),
+            }
+        } else {
+            let list = list(state, &query);
+            respond(&mut stream, "200 OK", &list);
+        }
+    }
+}
+
+/// The objects a request is about.
+struct Query {
+    resource: String,
+    /// `None` stands for all namespaces.
+    namespace: Option<String>,
+    name: Option<String>,
+    /// Whether a single object is requested by its name in the path, rather than selected by a field selector.
+    get: bool,
+}
+
+impl Query {
+    fn parse(path: &str, params: &BTreeMap<&str, &str>) -> Option<Self> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let (resource, namespace, name) = match segments.as_slice() {
+            ["api", "v1", resource] => (resource, None, None),
+            ["api", "v1", "namespaces", ns, resource] => (resource, Some(ns.to_string()), None),
+            ["api", "v1", "namespaces", ns, resource, name] => {
+                (resource, Some(ns.to_string()), Some(name.to_string()))
+            }
+            _ => return None,
+        };
+
+        // only the selection by name is supported
+        let selected = params
+            .get("fieldSelector")
+            .map(|s| s.replace("%3D", "=").replace("%3d", "="))
+            .and_then(|s| s.strip_prefix("metadata.name=").map(|n| n.to_owned()));
+
+        Some(Query {
+            resource: resource.to_string(),
+            namespace,
+            get: name.is_some(),
+            name: name.or(selected),
+        })
+    }
+
+    fn matches(&self, resource: &str, object: &Value) -> bool {
+        let meta = &object["metadata"];
+        self.resource == resource
+            && (self.namespace.is_none() || meta["namespace"].as_str() == self.namespace.as_deref())
+            && (self.name.is_none() || meta["name"].as_str() == self.name.as_deref())
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+    let _ = stream.write_all(
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+        .as_bytes(),
+    );
+}
+
+fn get(state: &(Mutex<State>, Condvar), query: &Query) -> Option<String> {
+    let state = state.0.lock().unwrap();
+    state
+        .objects
+        .iter()
+        .find(|((resource, _), object)| query.matches(resource, object))
+        .map(|(_, object)| object.to_string())
+}
+
+fn list(state: &(Mutex<State>, Condvar), query: &Query) -> String {
+    let mut state = state.0.lock().unwrap();
+    *state.lists.entry(query.resource.clone()).or_default() += 1;
+
+    let items: Vec<&Value> = state
+        .objects
+        .iter()
+        .filter(|((resource, _), object)| query.matches(resource, object))
+        .map(|(_, object)| object)
+        .collect();
+    format!(
+        r#"{{"kind": "List", "apiVersion": "v1", "metadata": {{"resourceVersion": "{}"}}, "items": {}}}"#,
+        state.version,
+        serde_json::to_string(&items).unwrap()
+    )
+}
+
+/// Streams the changes after the version until the watch expires or is disconnected.
+fn watch(stream: &mut TcpStream, state: &(Mutex<State>, Condvar), query: &Query, mut version: u64) {
+    let (lock, changed) = state;
+    let (expirations, disconnects) = {
+        let mut state = lock.lock().unwrap();
+        *state.watches.entry(query.resource.clone()).or_default() += 1;
+        (state.expirations, state.disconnects)
+    };
+
+    if stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        )
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        let lines = {
+            let mut state = lock.lock().unwrap();
+            while state.version <= version
+                && state.expirations == expirations
+                && state.disconnects == disconnects
+            {
+                state = changed.wait(state).unwrap();
+            }
+
+            if state.disconnects != disconnects {
+                break;
+            }
+            if state.expirations != expirations || version < state.compacted {
+                vec![
+                    r#"{"type": "ERROR", "object": {"status": "Failure", "message": "too old resource version", "reason": "Expired", "code": 410}}"#
+                        .to_string(),
+                ]
+            } else {
+                let lines = state
+                    .history
+                    .iter()
+                    .filter(|(v, _, resource, object)| {
+                        *v > version && query.matches(resource, object)
+                    })
+                    .map(|(_, kind, _, object)| {
+                        format!(r#"{{"type": "{}", "object": {}}}"#, kind, object)
+                    })
+                    .collect();
+                version = state.version;
+                lines
+            }
+        };
+
+        let expired = lines.iter().any(|l| l.contains("\"ERROR\""));
+        for line in lines {
+            let line = format!("{}\n", line);
+            if stream
+                .write_all(format!("{:x}\r\n{}\r\n", line.len(), line).as_bytes())
+                .is_err()
+            {
+                return;
+            }
+        }
+        if expired {
+            break;
+        }
+    }
+
+    let _ = stream.write_all(b"0\r\n\r\n");
+}
+
+/// A process killed when dropped.
+pub struct Running(pub Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Starts a shell recording each SIGHUP it receives as a line in the file, and waits until it is ready to do so.
+pub fn signal_recorder(log: &Path) -> Running {
+    let ready = log.with_extension("ready");
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "trap 'echo HUP >> {}' HUP; touch {}; while true; do sleep 0.1; done",
+            log.display(),
+            ready.display()
+        ))
+        .spawn()
+        .unwrap();
+    let running = Running(child);
+
+    eventually("the signal recorder to start", || ready.exists());
+
+    running
+}
+
+/// The number of signals recorded in the file.
+pub fn signals(log: &Path) -> usize {
+    fs::read_to_string(log)
+        .map(|s| s.lines().count())
+        .unwrap_or(0)
+}
+
+/// Runs cm-bump against the server with the provided arguments. Its logs are off unless `CM_LOG` is set.
+pub fn cm_bump(server: &FakeApiServer, workdir: &Path, args: &[&str]) -> Running {
+    // the logs are only useful when debugging the tests
+    let log = std::env::var("CM_LOG").unwrap_or_else(|_| "off".into());
+    Running(command(server, workdir, args, &log).spawn().unwrap())
+}
+
+/// Runs cm-bump against the server with the provided arguments, writing all its logs to the file.
+pub fn cm_bump_logged(
+    server: &FakeApiServer,
+    workdir: &Path,
+    args: &[&str],
+    log_file: &Path,
+) -> Running {
+    let log = fs::File::create(log_file).unwrap();
+    Running(
+        command(server, workdir, args, "warn,cm_bump=trace")
+            .stderr(log)
+            .spawn()
+            .unwrap(),
+    )
+}
+
+fn command(server: &FakeApiServer, workdir: &Path, args: &[&str], log: &str) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_cm-bump"));
+    command
+        .args(args)
+        .env("KUBECONFIG", server.kubeconfig(workdir))
+        .env_remove("KUBERNETES_SERVICE_HOST")
+        .env_remove("KUBERNETES_SERVICE_PORT")
+        .env("CM_LOG", log)
+        .stdout(Stdio::null());
+    command
+}
+
+/// Waits for the condition to become true, failing the test if it doesn't within 10 seconds.
+pub fn eventually(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        if Instant::now() > deadline {
+            panic!("Timed out waiting for {}", what);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+pub fn read(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok()
+}